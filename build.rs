@@ -10,14 +10,20 @@ fn main() {
     let headers = [
         "libavcodec/avcodec.h",
         "libavutil/avutil.h",
+        "libavutil/dict.h",
+        "libavutil/mastering_display_metadata.h",
+        "libavutil/mathematics.h",
+        "libavutil/motion_vector.h",
         "libavutil/opt.h",
         "libavutil/mem.h",
         "libavutil/imgutils.h",
         "libavutil/pixdesc.h",
+        "libswscale/swscale.h",
     ];
 
     let lib1 = pkg_config::probe_library("libavcodec").expect("find libavcodec");
     let lib2 = pkg_config::probe_library("libavutil").expect("find libavutil");
+    let lib3 = pkg_config::probe_library("libswscale").expect("find libswscale");
 
     let mut meta_header: Vec<_> = headers
         .iter()
@@ -26,11 +32,14 @@ fn main() {
 
     meta_header.push("const int AVErrorEAgain = AVERROR(EAGAIN);\n".into());
     meta_header.push("const int AVErrorEof = AVERROR_EOF;\n".into());
+    meta_header.push("const int AVErrorENoMem = AVERROR(ENOMEM);\n".into());
+    meta_header.push("const int64_t AVNoPtsValue = AV_NOPTS_VALUE;\n".into());
 
     let includes = lib1
         .include_paths
         .iter()
         .chain(lib2.include_paths.iter())
+        .chain(lib3.include_paths.iter())
         .map(|path| format!("-I{}", path.to_string_lossy()));
 
     println!("cargo:rerun-if-changed=src/log-to-string.c");
@@ -45,6 +54,10 @@ fn main() {
         .allowlist_item("avcodec.*")
         .allowlist_item("FF_.*")
         .allowlist_item("av_opt_set")
+        .allowlist_item("av_opt_set_int")
+        .allowlist_item("av_opt_get_int")
+        .allowlist_item("av_opt_next")
+        .allowlist_item("av_dict_.*")
         .allowlist_item("av_codec_.*")
         .allowlist_item("av_frame_.*")
         .allowlist_item("av_init_packet")
@@ -53,8 +66,12 @@ fn main() {
         .allowlist_item("av_strerror")
         .allowlist_item("av_log_set_level")
         .allowlist_item("av_malloc")
+        .allowlist_item("av_mallocz")
         .allowlist_item("av_image_.*")
+        .allowlist_item("av_rescale_q")
         .allowlist_item("av_pix_.*")
+        .allowlist_item("sws_.*")
+        .allowlist_item("SWS_.*")
         .allowlist_item("log_to_string.*")
         .default_enum_style(EnumVariation::Rust {
             non_exhaustive: false,
@@ -0,0 +1,228 @@
+use std::ffi::{c_int, c_void};
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, PoisonError, Weak};
+
+use super::{sys, PixelFormat};
+
+/// Alignment of the planes and line sizes handed to the decoder.
+const FRAME_ALIGN: usize = 64;
+
+/// Padding the decoder may write past the end of a plane, as in `avcodec_default_get_buffer2`.
+const FRAME_PADDING: usize = 16 + FRAME_ALIGN - 1;
+
+/// Memory for one plane of a decoded frame. It is dropped once libavcodec and all frames
+/// referencing it are done with it.
+pub type FrameBuffer = Box<dyn AsMut<[u8]> + Send>;
+
+/// Provides the memory decoders write frames to, see [`DecoderConfig::allocator`].
+///
+/// The decoder asks for one buffer per plane. Sizes include room for aligning the start of the
+/// buffer and the padding libavcodec needs, so the allocator doesn't have to care about either.
+/// With frame threading `allocate` is called from the decoding threads.
+///
+/// [`DecoderConfig::allocator`]: crate::DecoderConfig::allocator
+pub trait FrameAllocator: fmt::Debug + Send + Sync {
+    /// Allocate a buffer of at least `size` bytes, or `None` if out of memory.
+    fn allocate(&self, size: usize) -> Option<FrameBuffer>;
+}
+
+/// A [`FrameAllocator`] shared between decoders, compared by identity.
+#[derive(Debug, Clone)]
+pub struct SharedAllocator(pub Arc<dyn FrameAllocator>);
+
+impl SharedAllocator {
+    pub fn new(allocator: impl FrameAllocator + 'static) -> Self {
+        SharedAllocator(Arc::new(allocator))
+    }
+}
+
+impl PartialEq for SharedAllocator {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for SharedAllocator {}
+
+/// A [`FrameAllocator`] reusing the buffers of dropped frames.
+#[derive(Debug, Clone, Default)]
+pub struct PoolAllocator {
+    free: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+/// Buffers kept around for reuse, beyond this they are freed.
+const MAX_FREE_BUFFERS: usize = 32;
+
+struct PooledBuffer {
+    data: Vec<u8>,
+    pool: Weak<Mutex<Vec<Vec<u8>>>>,
+}
+
+impl FrameAllocator for PoolAllocator {
+    fn allocate(&self, size: usize) -> Option<FrameBuffer> {
+        let reused = {
+            // The free list is valid even if a thread panicked while holding the lock.
+            let mut free = self.free.lock().unwrap_or_else(PoisonError::into_inner);
+            free.iter()
+                .position(|b| b.len() == size)
+                .map(|i| free.swap_remove(i))
+        };
+
+        Some(Box::new(PooledBuffer {
+            data: reused.unwrap_or_else(|| vec![0; size]),
+            pool: Arc::downgrade(&self.free),
+        }))
+    }
+}
+
+impl AsMut<[u8]> for PooledBuffer {
+    fn as_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        let Some(pool) = self.pool.upgrade() else {
+            return;
+        };
+
+        let mut free = pool.lock().unwrap_or_else(PoisonError::into_inner);
+        if free.len() < MAX_FREE_BUFFERS {
+            free.push(std::mem::take(&mut self.data));
+        }
+    }
+}
+
+/// `get_buffer2` callback allocating the planes from the [`FrameAllocator`] in `opaque`.
+///
+/// **SAFETY:** `(*ctx).opaque` must point to an `Arc<dyn FrameAllocator>` that outlives `ctx`.
+pub(crate) unsafe extern "C" fn get_buffer2(
+    ctx: *mut sys::AVCodecContext,
+    frame: *mut sys::AVFrame,
+    flags: c_int,
+) -> c_int {
+    // Unwinding into libavcodec would abort, so a panicking allocator fails the frame instead.
+    let res = panic::catch_unwind(AssertUnwindSafe(|| allocate_frame(ctx, frame, flags)));
+    res.unwrap_or_else(|_| {
+        for buf in &mut (*frame).buf {
+            sys::av_buffer_unref(buf);
+        }
+        sys::AVErrorENoMem
+    })
+}
+
+/// **SAFETY:** As for [`get_buffer2`].
+unsafe fn allocate_frame(
+    ctx: *mut sys::AVCodecContext,
+    frame: *mut sys::AVFrame,
+    flags: c_int,
+) -> c_int {
+    let dr1 = (*(*ctx).codec).capabilities & sys::AV_CODEC_CAP_DR1 as i32 != 0;
+    let format = PixelFormat::from_av((*frame).format);
+    let Some(format) = format.filter(|_| dr1 && (*ctx).hw_frames_ctx.is_null()) else {
+        // The codec can't use custom buffers, or the format is one we can't lay out.
+        return sys::avcodec_default_get_buffer2(ctx, frame, flags);
+    };
+
+    let allocator = &*((*ctx).opaque as *const Arc<dyn FrameAllocator>);
+
+    let (mut width, mut height) = ((*frame).width, (*frame).height);
+    let mut stride_align = [0; sys::AV_NUM_DATA_POINTERS as usize];
+    sys::avcodec_align_dimensions2(ctx, &mut width, &mut height, stride_align.as_mut_ptr());
+
+    // Widen until every line size is suitably aligned, like the default allocator does, which
+    // keeps the ratios between the line sizes of the planes.
+    let plane_count = format.plane_count();
+    let strides = loop {
+        let strides: Vec<_> = (0..plane_count)
+            .map(|i| format.line_size(i, width as usize))
+            .collect();
+        let aligned = strides
+            .iter()
+            .zip(stride_align)
+            .all(|(&stride, align)| align <= 0 || stride % align as usize == 0);
+        if aligned {
+            break strides;
+        }
+        width += width & !(width - 1);
+    };
+
+    for (i, &stride) in strides.iter().enumerate() {
+        let size = stride * format.plane_height(i, height as usize) + FRAME_PADDING;
+        let buf = allocate_plane(allocator.as_ref(), size);
+        if buf.is_null() {
+            for buf in &mut (*frame).buf {
+                sys::av_buffer_unref(buf);
+            }
+            return sys::AVErrorENoMem;
+        }
+
+        (*frame).buf[i] = buf;
+        (*frame).data[i] = (*buf).data;
+        (*frame).linesize[i] = stride as i32;
+    }
+    (*frame).extended_data = (*frame).data.as_mut_ptr();
+
+    0
+}
+
+/// Wrap a buffer of `size` bytes, aligned to [`FRAME_ALIGN`], from `allocator` in an AVBufferRef.
+unsafe fn allocate_plane(allocator: &dyn FrameAllocator, size: usize) -> *mut sys::AVBufferRef {
+    let Some(mut buffer) = allocator.allocate(size + FRAME_ALIGN - 1) else {
+        return std::ptr::null_mut();
+    };
+
+    let data: &mut [u8] = (*buffer).as_mut();
+    if data.len() < size + FRAME_ALIGN - 1 {
+        return std::ptr::null_mut();
+    }
+    let offset = data.as_ptr().align_offset(FRAME_ALIGN);
+    let data = data[offset..].as_mut_ptr();
+
+    let opaque = Box::into_raw(Box::new(buffer));
+    let buf = sys::av_buffer_create(
+        data,
+        // NB: The type expected here differs based on the underlying version of libavutil, see
+        // Decoder::decode.
+        #[allow(clippy::useless_conversion)]
+        size.try_into().unwrap(),
+        Some(free_frame_buffer),
+        opaque.cast(),
+        0,
+    );
+    if buf.is_null() {
+        drop(Box::from_raw(opaque));
+    }
+
+    buf
+}
+
+extern "C" fn free_frame_buffer(opaque: *mut c_void, _data: *mut u8) {
+    // Unwinding into libavcodec would abort, so if the buffer panics on drop whatever it didn't
+    // free is leaked.
+    let _ = panic::catch_unwind(|| unsafe {
+        drop(Box::<FrameBuffer>::from_raw(opaque.cast()));
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pool_allocator_reuses_buffers() {
+        let allocator = PoolAllocator::default();
+
+        let mut buffer = allocator.allocate(1000).unwrap();
+        let data: &mut [u8] = (*buffer).as_mut();
+        assert_eq!(data.len(), 1000);
+        let ptr = data.as_ptr();
+        drop(buffer);
+
+        let mut buffer = allocator.allocate(1000).unwrap();
+        let data: &mut [u8] = (*buffer).as_mut();
+        assert_eq!(data.as_ptr(), ptr);
+    }
+}
@@ -1,21 +1,31 @@
 use std::ffi::c_void;
 use std::ptr;
+use std::sync::Arc;
 
+use crate::allocator::{get_buffer2, FrameAllocator, SharedAllocator};
+use crate::dict::Dictionary;
+use crate::opaque::Opaque;
+use crate::side_data::frame_side_data;
 use crate::Packet;
 use crate::PaddedData;
 use crate::MAX_PLANES;
 
 use super::{
     av_log_set_callback, err_code_to_string, log_callback, set_log_level, sys, Codec, CodecKind,
-    Error, Frame, PixelFormat,
+    Error, Frame, FrameSideData, Metadata, PixelFormat, Rational,
 };
 
-use tracing::Level;
+use tracing::{trace, Level};
 
 pub struct Decoder {
     ctx: *mut sys::AVCodecContext,
-    /// Maps rotation values to the PTS of the incoming packet.
-    pts_map: PtsMap,
+    /// The allocator `ctx.opaque` points to for `get_buffer2`.
+    allocator: *mut Arc<dyn FrameAllocator>,
+    limits: DecoderLimits,
+    /// Set after a decode error or a corrupt frame, cleared by the next intact keyframe.
+    needs_keyframe: bool,
+    /// Set by drain, the context has to be reset before it accepts packets again.
+    drained: bool,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Default)]
@@ -24,6 +34,101 @@ pub struct DecoderConfig {
     pub thread_count: u32,
     /// Type of threading.
     pub thread_type: DecodeThreadType,
+    /// How strictly to check the bitstream for errors.
+    pub error_recognition: ErrorRecognition,
+    /// How to hide errors in damaged frames.
+    pub error_concealment: ErrorConcealment,
+    /// Output frames even if they are corrupt, flagged with [`Frame::is_corrupt`]. Otherwise
+    /// the decoder drops them.
+    ///
+    /// For live video a damaged frame is usually better than none, for archival ingestion
+    /// combine `false` here with [`ErrorRecognition::Strict`] to fail fast.
+    pub output_corrupt: bool,
+    /// Output all frames, including the ones before the first keyframe.
+    pub show_all: bool,
+    /// Bounds on the resources a stream can make the decoder use.
+    pub limits: DecoderLimits,
+    /// Export motion vectors as [`FrameSideData::MotionVectors`](crate::FrameSideData).
+    ///
+    /// Closed captions, SEI user data, HDR metadata and display matrices are exported by the
+    /// decoders that support them without any configuration.
+    pub export_motion_vectors: bool,
+    /// Where to allocate the planes of decoded frames, `None` for the libavcodec allocator.
+    ///
+    /// Hardware decoders and codecs that can't decode into custom buffers always use the
+    /// libavcodec allocator.
+    pub allocator: Option<SharedAllocator>,
+    /// Codec configuration from the container, e.g. the `avcC` or `hvcC` box of an MP4 track.
+    /// Needed for streams that don't carry their parameter sets in-band.
+    pub extradata: Vec<u8>,
+    /// Time base of the packet timestamps, reported on the decoded frames.
+    ///
+    /// `None` takes the time base of the first packet that has one. Packets in any other time base
+    /// are rescaled to it.
+    pub time_base: Option<Rational>,
+    /// Extra AVOptions passed to the codec when opening it.
+    ///
+    /// Opening fails if the codec doesn't recognise any of them.
+    pub options: Vec<(String, String)>,
+}
+
+/// Limits for decoding untrusted streams.
+///
+/// `None` means no limit beyond the libavcodec defaults. Exceeding a limit fails
+/// [`Decoder::decode`] with [`Error::LimitExceeded`] and resets the decoder, so that nothing from
+/// the offending stream is output.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct DecoderLimits {
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    /// Maximum number of pixels per frame. libavcodec refuses to allocate larger frames.
+    pub max_pixels: Option<u64>,
+    /// Maximum number of reference frames the stream may declare.
+    ///
+    /// Only applies to H.264, where it's checked against the SPS once the packet carrying it was
+    /// sent to the decoder. The decoder has allocated its buffers for that packet by then, but
+    /// no frame is output. Setting it turns frame threading off for H.264, since frame threads
+    /// don't report the count back. Other decoders ignore it.
+    pub max_ref_frames: Option<u32>,
+    /// Upper bound for `DecoderConfig::thread_count`, also applied when the count is automatic.
+    pub max_thread_count: Option<u32>,
+    /// Maximum size of a packet passed to [`Decoder::decode`], in bytes.
+    pub max_packet_size: Option<usize>,
+}
+
+impl DecoderLimits {
+    /// The pixel limit for libavcodec, taking the width and height limits into account.
+    fn max_pixels(&self) -> Option<u64> {
+        let dimensions = self
+            .max_width
+            .zip(self.max_height)
+            .map(|(w, h)| w as u64 * h as u64);
+
+        match (self.max_pixels, dimensions) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    fn thread_count(&self, thread_count: u32) -> u32 {
+        match self.max_thread_count {
+            Some(max) if thread_count == 0 || thread_count > max => max,
+            _ => thread_count,
+        }
+    }
+
+    fn check_size(&self, width: u32, height: u32) -> Result<(), Error> {
+        check_limit("width", width as u64, self.max_width.map(u64::from))?;
+        check_limit("height", height as u64, self.max_height.map(u64::from))?;
+        check_limit("pixels", width as u64 * height as u64, self.max_pixels())
+    }
+}
+
+fn check_limit(what: &'static str, value: u64, limit: Option<u64>) -> Result<(), Error> {
+    match limit {
+        Some(limit) if value > limit => Err(Error::LimitExceeded(what, value, limit)),
+        _ => Ok(()),
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Default)]
@@ -37,9 +142,112 @@ pub enum DecodeThreadType {
     Default,
 }
 
-struct PtsMap {
-    map: [(i64, usize); 16],
-    cur: usize,
+/// How strictly the decoder checks the bitstream, see `AVCodecContext.err_recognition`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ErrorRecognition {
+    // Whatever is the default of the decoder
+    #[default]
+    Default,
+    // Ignore errors and decode as much as possible
+    Ignore,
+    // Treat spec violations that are not seen in the wild as errors
+    Careful,
+    // Treat all spec violations as errors
+    Compliant,
+    // Check everything, including CRCs, and fail on the first error
+    Strict,
+}
+
+/// Error concealment strategies, see `AVCodecContext.error_concealment`.
+///
+/// The default matches the libavcodec default of guessing motion vectors and deblocking.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ErrorConcealment {
+    /// Guess the motion vectors of damaged blocks from their neighbours.
+    pub guess_mvs: bool,
+    /// Deblock concealed blocks.
+    pub deblock: bool,
+    /// Prefer copying from the previous frame over intra prediction.
+    pub favor_inter: bool,
+}
+
+impl Default for ErrorConcealment {
+    fn default() -> Self {
+        ErrorConcealment {
+            guess_mvs: true,
+            deblock: true,
+            favor_inter: false,
+        }
+    }
+}
+
+impl ErrorRecognition {
+    fn as_av(&self) -> Option<i32> {
+        let flags = match self {
+            ErrorRecognition::Default => return None,
+            ErrorRecognition::Ignore => sys::AV_EF_IGNORE_ERR,
+            ErrorRecognition::Careful => sys::AV_EF_CAREFUL,
+            ErrorRecognition::Compliant => sys::AV_EF_CAREFUL | sys::AV_EF_COMPLIANT,
+            ErrorRecognition::Strict => {
+                sys::AV_EF_CRCCHECK
+                    | sys::AV_EF_BITSTREAM
+                    | sys::AV_EF_BUFFER
+                    | sys::AV_EF_EXPLODE
+                    | sys::AV_EF_CAREFUL
+                    | sys::AV_EF_COMPLIANT
+            }
+        };
+
+        Some(flags as i32)
+    }
+}
+
+impl ErrorConcealment {
+    fn as_av(&self) -> i32 {
+        let mut flags = 0;
+        if self.guess_mvs {
+            flags |= sys::FF_EC_GUESS_MVS;
+        }
+        if self.deblock {
+            flags |= sys::FF_EC_DEBLOCK;
+        }
+        if self.favor_inter {
+            flags |= sys::FF_EC_FAVOR_INTER;
+        }
+
+        flags as i32
+    }
+}
+
+/// Errors the decoder ran into while decoding a frame, see `AVFrame.decode_error_flags`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DecodeErrors {
+    /// The bitstream is damaged.
+    pub invalid_bitstream: bool,
+    /// A reference frame is missing, e.g. because a packet was lost.
+    pub missing_reference: bool,
+    /// Error concealment was applied to the frame.
+    pub concealment_active: bool,
+    /// Some slices could not be decoded.
+    pub decode_slices: bool,
+}
+
+impl DecodeErrors {
+    fn from_av(flags: i32) -> Self {
+        let has = |flag: u32| flags & flag as i32 != 0;
+
+        DecodeErrors {
+            invalid_bitstream: has(sys::FF_DECODE_ERROR_INVALID_BITSTREAM),
+            missing_reference: has(sys::FF_DECODE_ERROR_MISSING_REFERENCE),
+            concealment_active: has(sys::FF_DECODE_ERROR_CONCEALMENT_ACTIVE),
+            decode_slices: has(sys::FF_DECODE_ERROR_DECODE_SLICES),
+        }
+    }
+
+    /// Whether no errors were reported.
+    pub fn is_empty(&self) -> bool {
+        *self == DecodeErrors::default()
+    }
 }
 
 /// A single frame of video or audio.
@@ -62,13 +270,28 @@ impl Decoder {
         }
 
         let codec = codec.ptr;
+        let mut limits = config.limits.clone();
+        // SAFETY: The codec pointer is valid for the lifetime of the program.
+        if unsafe { (*codec).id } != sys::AVCodecID::AV_CODEC_ID_H264 {
+            // Other decoders don't report the count, see DecoderLimits::max_ref_frames.
+            limits.max_ref_frames = None;
+        }
+
         let ctx: *mut sys::AVCodecContext = unsafe { sys::avcodec_alloc_context3(codec) };
         if ctx.is_null() {
             return Err(Error::CreateContextFailed);
         }
 
+        let allocator = match &config.allocator {
+            Some(allocator) => Box::into_raw(Box::new(allocator.0.clone())),
+            None => ptr::null_mut(),
+        };
+
         unsafe {
-            (*ctx).thread_count = config.thread_count as i32;
+            (*ctx).thread_count = config.limits.thread_count(config.thread_count) as i32;
+            if let Some(max_pixels) = config.limits.max_pixels() {
+                (*ctx).max_pixels = max_pixels.try_into().unwrap_or(i64::MAX);
+            }
             match config.thread_type {
                 DecodeThreadType::Frame => {
                     (*ctx).thread_type = sys::FF_THREAD_FRAME as i32;
@@ -78,20 +301,77 @@ impl Decoder {
                 }
                 DecodeThreadType::Default => {}
             };
+            if limits.max_ref_frames.is_some() {
+                // Only the slice threads update ctx.refs, see DecoderLimits::max_ref_frames.
+                (*ctx).thread_type = sys::FF_THREAD_SLICE as i32;
+            }
+
+            if let Some(time_base) = config.time_base {
+                (*ctx).pkt_timebase = time_base.into();
+            }
+
+            if let Some(flags) = config.error_recognition.as_av() {
+                (*ctx).err_recognition = flags;
+            }
+            (*ctx).error_concealment = config.error_concealment.as_av();
+            if config.output_corrupt {
+                (*ctx).flags |= sys::AV_CODEC_FLAG_OUTPUT_CORRUPT as i32;
+            }
+            if config.show_all {
+                (*ctx).flags2 |= sys::AV_CODEC_FLAG2_SHOW_ALL as i32;
+            }
+
+            if config.export_motion_vectors {
+                (*ctx).export_side_data |= sys::AV_CODEC_EXPORT_DATA_MVS as i32;
+            }
+
+            if !allocator.is_null() {
+                (*ctx).opaque = allocator.cast();
+                (*ctx).get_buffer2 = Some(get_buffer2);
+            }
+
+            // Carry the packet opaque_ref over to the decoded frames.
+            (*ctx).flags |= sys::AV_CODEC_FLAG_COPY_OPAQUE as i32;
         }
 
         let dec = Decoder {
             ctx,
-            pts_map: PtsMap::default(),
+            allocator,
+            limits,
+            needs_keyframe: false,
+            drained: false,
         };
 
-        // TODO: options
+        if !config.extradata.is_empty() {
+            // libavcodec owns the extradata and frees it with the context, and expects it to be
+            // padded like packet data.
+            let len = config.extradata.len();
+            let extradata: *mut u8 =
+                unsafe { sys::av_mallocz(len + sys::AV_INPUT_BUFFER_PADDING_SIZE as usize).cast() };
+            if extradata.is_null() {
+                return Err(Error::AlllocateFailed("av_mallocz for extradata"));
+            }
 
-        let err = unsafe { sys::avcodec_open2(ctx, codec, ptr::null_mut()) };
+            unsafe {
+                ptr::copy_nonoverlapping(config.extradata.as_ptr(), extradata, len);
+                (*ctx).extradata = extradata;
+                (*ctx).extradata_size = len as i32;
+            }
+        }
+
+        let mut options = Dictionary::new(&config.options)?;
+
+        let err = unsafe { sys::avcodec_open2(ctx, codec, options.as_mut_ptr()) };
         if err < 0 {
             return Err(Error::CodecOpenError(err, err_code_to_string(err)));
         }
 
+        // avcodec_open2 removes the options it consumed, anything left is unknown to the codec.
+        let unused = options.keys();
+        if !unused.is_empty() {
+            return Err(Error::UnusedOptions(unused));
+        }
+
         Ok(dec)
     }
 
@@ -102,14 +382,69 @@ impl Decoder {
         &mut self,
         packet: T,
     ) -> Result<impl Iterator<Item = Result<impl Frame, Error>> + '_, Error> {
+        let size = packet
+            .data()
+            .len()
+            .saturating_sub(sys::AV_INPUT_BUFFER_PADDING_SIZE as usize);
+        check_limit(
+            "packet size",
+            size as u64,
+            self.limits.max_packet_size.map(|max| max as u64),
+        )?;
+
+        if self.drained {
+            self.reset();
+        }
+
         let mut pkt = unsafe { sys::av_packet_alloc() };
 
         if pkt.is_null() {
             return Err(Error::AlllocateFailed("av_malloc for Decoder::decode"));
         }
 
-        let pts = packet.pts();
-        self.pts_map.set(pts, packet.rotation());
+        let mut pts = packet.pts();
+        let mut dts = packet.dts();
+        let mut duration = packet.duration();
+
+        // SAFETY: The pointer is valid while self is alive.
+        let time_base: Rational = unsafe { (*self.ctx).pkt_timebase }.into();
+        if let Some(packet_time_base) = packet.time_base() {
+            if time_base.num == 0 {
+                // Not configured, so the stream keeps the time base it came with.
+                unsafe { (*self.ctx).pkt_timebase = packet_time_base.into() };
+            } else if packet_time_base != time_base {
+                // A missing pts or dts stays AV_NOPTS_VALUE, see Rational::rescale.
+                pts = packet_time_base.rescale(pts, time_base);
+                dts = packet_time_base.rescale(dts, time_base);
+                duration = packet_time_base.rescale(duration, time_base);
+            }
+        }
+        let opaque_data = Opaque {
+            rotation: packet.rotation(),
+            metadata: packet.metadata(),
+        };
+
+        for side_data in packet.side_data() {
+            let (kind, data) = match side_data.as_av() {
+                Ok(v) => v,
+                Err(kind) => {
+                    // E.g. BlockAdditional data from libvpx, which only means something to a
+                    // muxer.
+                    trace!("Skipping packet side data of type {kind} when decoding");
+                    continue;
+                }
+            };
+
+            // SAFETY: pkt is valid, and av_packet_new_side_data returns a buffer of `data.len()`.
+            unsafe {
+                let dst = sys::av_packet_new_side_data(pkt, kind, data.len());
+                if dst.is_null() {
+                    sys::av_packet_free(&mut pkt);
+                    return Err(Error::AlllocateFailed("av_packet_new_side_data"));
+                }
+                ptr::copy_nonoverlapping(data.as_ptr(), dst, data.len());
+            }
+        }
 
         let data = packet.data();
 
@@ -120,7 +455,9 @@ impl Decoder {
         let data_ptr = data.as_ptr();
 
         let buf = if let Some(buf) = packet.as_avcodec_buf_ref() {
-            buf
+            // The buffer is unreferenced when `pkt` is freed below, so take our own reference and
+            // leave the one held by `packet` alone.
+            unsafe { sys::av_buffer_ref(buf) }
         } else {
             let droppable = packet.into_droppable();
             let boxed = Box::new(droppable);
@@ -147,6 +484,10 @@ impl Decoder {
             (*pkt).buf = buf;
             (*pkt).data = data_ptr.cast_mut();
             (*pkt).pts = pts;
+            (*pkt).dts = dts;
+            (*pkt).duration = duration;
+            // With AV_CODEC_FLAG_COPY_OPAQUE this ends up on the frames decoded from the packet.
+            (*pkt).opaque_ref = opaque_data.into_buffer();
             // This should be the size of the data without the padding
             (*pkt).size = (len as i32) - sys::AV_INPUT_BUFFER_PADDING_SIZE as i32;
         }
@@ -168,14 +509,81 @@ impl Decoder {
             sys::av_packet_free(&mut pkt);
         }
         if ret < 0 {
+            self.needs_keyframe = true;
+            return Err(Error::DecodePacketFailed(ret, err_code_to_string(ret)));
+        }
+
+        // The packet may have carried new parameter sets, check them before any frames are made
+        // from them.
+        if let Err(e) = self.check_limits() {
+            self.reset();
+            self.needs_keyframe = true;
+            return Err(e);
+        }
+
+        Ok(DecoderIterator {
+            dec: self,
+            ended: false,
+        })
+    }
+
+    /// Signal end of stream to the decoder.
+    ///
+    /// Returns an iterator over the frames still held by the decoder. The next call to
+    /// [`Decoder::decode`] resets the decoder to start a new stream, discarding any frames not
+    /// taken from the iterator.
+    pub fn drain(&mut self) -> Result<impl Iterator<Item = Result<impl Frame, Error>> + '_, Error> {
+        let ret = unsafe { sys::avcodec_send_packet(self.ctx, ptr::null()) };
+
+        // AVERROR_EOF means we are already draining, which is fine.
+        if ret < 0 && ret != sys::AVErrorEof {
             return Err(Error::DecodePacketFailed(ret, err_code_to_string(ret)));
         }
+        self.drained = true;
 
         Ok(DecoderIterator {
             dec: self,
             ended: false,
         })
     }
+
+    fn check_limits(&self) -> Result<(), Error> {
+        // SAFETY: The pointer is valid while self is alive.
+        let (width, height, refs) =
+            unsafe { ((*self.ctx).width, (*self.ctx).height, (*self.ctx).refs) };
+
+        self.limits.check_size(width as u32, height as u32)?;
+        check_limit(
+            "reference frames",
+            refs as u64,
+            self.limits.max_ref_frames.map(u64::from),
+        )
+    }
+
+    /// Whether the decoder is waiting for a keyframe to recover from an error.
+    ///
+    /// This is set when a packet fails to decode or a frame comes out corrupt, and cleared once
+    /// an intact keyframe is decoded or the decoder is [reset](Decoder::reset). While it's set
+    /// the caller should ask the sender for a keyframe, e.g. with an RTCP PLI, rate limited as
+    /// usual.
+    ///
+    /// Damaged packets are caught here, but a packet that went missing entirely may leave no
+    /// trace in the bitstream, so detect those from the transport's sequence numbers.
+    pub fn needs_keyframe(&self) -> bool {
+        self.needs_keyframe
+    }
+
+    /// Discard any buffered packets and frames.
+    ///
+    /// Use this when seeking, so that the same decoder can be fed packets from the new position.
+    /// Errors in the old stream no longer matter, so [`Decoder::needs_keyframe`] is cleared.
+    pub fn reset(&mut self) {
+        unsafe {
+            sys::avcodec_flush_buffers(self.ctx);
+        }
+        self.drained = false;
+        self.needs_keyframe = false;
+    }
 }
 
 extern "C" fn free_packet_droppable<T>(opaque: *mut c_void, _data: *mut u8) {
@@ -205,13 +613,36 @@ impl<'a> Iterator for DecoderIterator<'a> {
             return None;
         } else if ret < 0 {
             self.ended = true;
+            self.dec.needs_keyframe = true;
             return Some(Err(Error::ReceiveFrameFailed(ret, err_code_to_string(ret))));
         }
-        unsafe {
-            // This is a pointer but it's entirely opaque to libavcodec so we can use it to store
-            // some arbitrary pointer sized data.
-            (*frame.0).opaque = self.dec.pts_map.get(frame.pts()).unwrap_or(0) as *mut c_void;
-        };
+
+        // With frame threading the context lags behind, so check the frames too.
+        let size_check = self
+            .dec
+            .limits
+            .check_size(frame.width() as u32, frame.height() as u32);
+        if let Err(e) = size_check {
+            self.ended = true;
+            self.dec.reset();
+            self.dec.needs_keyframe = true;
+            return Some(Err(e));
+        }
+
+        if frame.is_corrupt() {
+            self.dec.needs_keyframe = true;
+        } else if frame.is_keyframe() {
+            self.dec.needs_keyframe = false;
+        }
+
+        // SAFETY: The pointers are valid while frame and dec are alive.
+        unsafe { (*frame.0).time_base = (*self.dec.ctx).pkt_timebase };
+
+        // SAFETY: The pointer is valid while frame is alive.
+        let format = unsafe { (*frame.0).format };
+        if PixelFormat::from_av(format).is_none() {
+            return Some(Err(Error::UnsupportedPixelFormat(format)));
+        }
 
         Some(Ok(frame))
     }
@@ -225,6 +656,17 @@ impl DecodedFrame {
         Self(ptr)
     }
 
+    fn opaque(&self) -> Option<&Opaque> {
+        // SAFETY: The pointer is valid while self is alive, and opaque_ref is either null or
+        // copied from a packet by libavcodec, where we put it in Decoder::decode.
+        unsafe { Opaque::from_buffer((*self.0).opaque_ref) }
+    }
+
+    fn is_keyframe(&self) -> bool {
+        // SAFETY: The pointer is valid while self is alive.
+        unsafe { (*self.0).flags & sys::AV_FRAME_FLAG_KEY as i32 != 0 }
+    }
+
     /// The presentation timestamp for this frame.
     ///
     /// This is an internal value from the Decoder instance. Not a real PTS.
@@ -247,42 +689,30 @@ impl Frame for DecodedFrame {
         unsafe { (*self.0).height as usize }
     }
 
-    fn plane_count(&self) -> usize {
+    fn pixel_format(&self) -> PixelFormat {
         // SAFETY: The pointer is valid while self is alive.
-        unsafe {
-            assert_eq!(
-                (*self.0).format,
-                PixelFormat::AV_PIX_FMT_YUV420P as i32,
-                "Only YUV420P is supported"
-            );
+        let format = unsafe { (*self.0).format };
 
-            3
-        }
+        // DecoderIterator only hands out frames in formats we know about.
+        PixelFormat::from_av(format).expect("a supported pixel format")
+    }
+
+    fn plane_count(&self) -> usize {
+        self.pixel_format().plane_count()
     }
 
     fn get_plane(&self, i: usize) -> &[u8] {
-        assert!(i < MAX_PLANES);
+        assert!(i < self.plane_count());
 
         // SAFETY:
         // * The pointer is valid while self is alive.
         // * The value calculated for `len` is correct
         unsafe {
-            assert_eq!(
-                (*self.0).format,
-                PixelFormat::AV_PIX_FMT_YUV420P as i32,
-                "Only YUV420P is supported"
-            );
             let ptr: *mut u8 = (*self.0).data[i];
 
-            let height = self.height();
+            let height = self.pixel_format().plane_height(i, self.height());
             let stride = self.get_stride(i);
-            let len = if i == 0 {
-                // Y
-                stride * height
-            } else {
-                // U & V
-                stride * (height / 2)
-            };
+            let len = stride * height;
 
             std::slice::from_raw_parts(ptr, len)
         }
@@ -293,12 +723,6 @@ impl Frame for DecodedFrame {
 
         // SAFETY: The pointer is valid while self is alive.
         unsafe {
-            assert_eq!(
-                (*self.0).format,
-                PixelFormat::AV_PIX_FMT_YUV420P as i32,
-                "Only YUV420P is supported"
-            );
-
             (*self.0).linesize[i]
                 .try_into()
                 .expect("Non negative linesize")
@@ -306,8 +730,33 @@ impl Frame for DecodedFrame {
     }
 
     fn rotation(&self) -> usize {
+        self.opaque().map(|o| o.rotation).unwrap_or(0)
+    }
+
+    fn time_base(&self) -> Option<Rational> {
+        // SAFETY: The pointer is valid while self is alive.
+        let time_base = unsafe { (*self.0).time_base };
+        (time_base.num != 0).then(|| time_base.into())
+    }
+
+    fn side_data(&self) -> Vec<FrameSideData<'_>> {
+        // SAFETY: The pointer and the side data are valid while self is alive.
+        unsafe { frame_side_data(self.0) }
+    }
+
+    fn is_corrupt(&self) -> bool {
         // SAFETY: The pointer is valid while self is alive.
-        unsafe { (*self.0).opaque as usize }
+        let flags = unsafe { (*self.0).flags };
+        flags & sys::AV_FRAME_FLAG_CORRUPT as i32 != 0 || !self.decode_errors().is_empty()
+    }
+
+    fn decode_errors(&self) -> DecodeErrors {
+        // SAFETY: The pointer is valid while self is alive.
+        DecodeErrors::from_av(unsafe { (*self.0).decode_error_flags })
+    }
+
+    fn metadata(&self) -> Option<Metadata> {
+        self.opaque().and_then(|o| o.metadata.clone())
     }
 
     fn pts(&self) -> i64 {
@@ -336,35 +785,485 @@ impl Drop for DecodedFrame {
     }
 }
 
-impl PtsMap {
-    fn set(&mut self, pts: i64, value: usize) {
-        self.map[self.cur] = (pts, value);
-        self.cur = (self.cur + 1) % self.map.len();
-    }
-
-    fn get(&self, pts: i64) -> Option<usize> {
-        self.map
-            .iter()
-            .find(|(p, _)| *p == pts)
-            .copied()
-            .map(|(_, v)| v)
-    }
-}
-
 impl Drop for Decoder {
     fn drop(&mut self) {
         unsafe {
             sys::avcodec_free_context(&mut self.ctx);
+
+            // Frames still alive hold on to their buffers, not to the allocator.
+            if !self.allocator.is_null() {
+                drop(Box::from_raw(self.allocator));
+            }
         }
         self.ctx = ptr::null_mut();
     }
 }
 
-impl Default for PtsMap {
-    fn default() -> Self {
-        Self {
-            map: [(-1, 0); 16],
-            cur: 0,
+#[cfg(test)]
+mod test {
+    use std::ops::Range;
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::test_util::{
+        decode_count, encode_stream, flush, h264, test_config, test_frame, x264, TestPacket,
+    };
+    use crate::{Encoder, EncoderConfig, FrameBuffer, PacketSideData, PaddedDataImpl};
+
+    /// Decode the packets and drain the decoder, taking the frames as DecodedFrame to get at
+    /// the fields `Frame` doesn't expose.
+    fn decode_frames(
+        dec: &mut Decoder,
+        packets: impl IntoIterator<Item = TestPacket>,
+    ) -> Vec<DecodedFrame> {
+        let mut frames = vec![];
+        for packet in packets {
+            drop(dec.decode(packet).unwrap());
+            frames.extend(decoded_frames(dec));
+        }
+        drop(dec.drain().unwrap());
+        frames.extend(decoded_frames(dec));
+
+        frames
+    }
+
+    fn decoded_frames(dec: &mut Decoder) -> Vec<DecodedFrame> {
+        let iter = DecoderIterator { dec, ended: false };
+        iter.collect::<Result<_, _>>().unwrap()
+    }
+
+    #[test]
+    fn test_unknown_option() {
+        let config = DecoderConfig {
+            options: vec![
+                ("flags2".to_string(), "+showall".to_string()),
+                ("no-such-option".to_string(), "1".to_string()),
+            ],
+            ..Default::default()
+        };
+        assert!(matches!(
+            Decoder::new(&h264(), &config),
+            Err(Error::UnusedOptions(options)) if options == ["no-such-option"]
+        ));
+    }
+
+    #[test]
+    fn test_error_handling_flags() {
+        let config = DecoderConfig {
+            error_recognition: ErrorRecognition::Careful,
+            error_concealment: ErrorConcealment {
+                guess_mvs: false,
+                deblock: true,
+                favor_inter: true,
+            },
+            output_corrupt: true,
+            show_all: true,
+            ..Default::default()
+        };
+        let dec = Decoder::new(&h264(), &config).unwrap();
+
+        // SAFETY: The pointer is valid while dec is alive.
+        let ctx = unsafe { &*dec.ctx };
+        assert_eq!(ctx.err_recognition, sys::AV_EF_CAREFUL as i32);
+        assert_eq!(
+            ctx.error_concealment,
+            (sys::FF_EC_DEBLOCK | sys::FF_EC_FAVOR_INTER) as i32
+        );
+        assert_ne!(ctx.flags & sys::AV_CODEC_FLAG_OUTPUT_CORRUPT as i32, 0);
+        assert_ne!(ctx.flags2 & sys::AV_CODEC_FLAG2_SHOW_ALL as i32, 0);
+
+        let dec = Decoder::new(&h264(), &DecoderConfig::default()).unwrap();
+        let ctx = unsafe { &*dec.ctx };
+        assert_eq!(
+            ctx.error_concealment,
+            (sys::FF_EC_GUESS_MVS | sys::FF_EC_DEBLOCK) as i32
+        );
+        assert_eq!(ctx.flags & sys::AV_CODEC_FLAG_OUTPUT_CORRUPT as i32, 0);
+        assert_eq!(ctx.flags2 & sys::AV_CODEC_FLAG2_SHOW_ALL as i32, 0);
+    }
+
+    #[test]
+    fn test_decode_after_partial_drain() {
+        let mut dec = Decoder::new(&h264(), &DecoderConfig::default()).unwrap();
+
+        let (packets, _) = encode_stream(&test_config(), 10);
+        for packet in packets {
+            dec.decode(packet).unwrap().for_each(|f| drop(f.unwrap()));
+        }
+        // Take a single frame and leave the rest in the drained decoder.
+        dec.drain().unwrap().take(1).for_each(|f| drop(f.unwrap()));
+
+        let (packets, _) = encode_stream(&test_config(), 10);
+        assert_eq!(decode_count(&mut dec, packets).unwrap(), 10);
+    }
+
+    #[test]
+    fn test_decode_rescales_timestamps() {
+        let config = DecoderConfig {
+            time_base: Some(Rational::new(1, 30)),
+            ..Default::default()
+        };
+        let mut dec = Decoder::new(&h264(), &config).unwrap();
+
+        let (mut packets, _) = encode_stream(&test_config(), 10);
+        for packet in &mut packets {
+            let i = packet.pts;
+            packet.time_base = Some(Rational::new(1, 90_000));
+            packet.pts = if i == 5 { sys::AVNoPtsValue } else { i * 3000 };
+            packet.dts = i * 3000;
+            packet.duration = 3000;
+        }
+
+        let frames = decode_frames(&mut dec, packets);
+        assert_eq!(frames.len(), 10);
+        for (i, frame) in frames.iter().enumerate() {
+            let i = i as i64;
+            let expected_pts = if i == 5 { sys::AVNoPtsValue } else { i };
+            // SAFETY: The pointer is valid while frame is alive.
+            let (dts, duration) = unsafe { ((*frame.0).pkt_dts, (*frame.0).duration) };
+
+            assert_eq!(frame.pts(), expected_pts);
+            assert_eq!(dts, i);
+            assert_eq!(duration, 1);
+        }
+    }
+
+    #[test]
+    fn test_decode_takes_packet_time_base() {
+        let mut dec = Decoder::new(&h264(), &DecoderConfig::default()).unwrap();
+
+        let (mut packets, _) = encode_stream(&test_config(), 10);
+        for packet in &mut packets {
+            packet.time_base = Some(Rational::new(1, 90_000));
+            packet.pts *= 3000;
+            packet.dts *= 3000;
+            packet.duration *= 3000;
+        }
+
+        let frames = decode_frames(&mut dec, packets);
+        assert_eq!(frames.len(), 10);
+        for (i, frame) in frames.iter().enumerate() {
+            assert_eq!(frame.pts(), i as i64 * 3000);
+            assert_eq!(frame.time_base(), Some(Rational::new(1, 90_000)));
+        }
+    }
+
+    #[test]
+    fn test_decode_with_side_data() {
+        let mut dec = Decoder::new(&h264(), &DecoderConfig::default()).unwrap();
+        let matrix = [0, 65536, 0, -65536, 0, 0, 0, 0, 1 << 30];
+
+        let (mut packets, _) = encode_stream(&test_config(), 5);
+        for packet in &mut packets {
+            packet.side_data = vec![
+                PacketSideData::DisplayMatrix(matrix),
+                // Not something the decoder takes, so it's skipped.
+                PacketSideData::Other(
+                    sys::AVPacketSideDataType::AV_PKT_DATA_MATROSKA_BLOCKADDITIONAL as u32,
+                    &[1, 2, 3],
+                ),
+            ];
+        }
+
+        let frames = decode_frames(&mut dec, packets);
+        assert_eq!(frames.len(), 5);
+        for frame in &frames {
+            assert!(frame
+                .side_data()
+                .contains(&FrameSideData::DisplayMatrix(matrix)));
+        }
+    }
+
+    #[test]
+    fn test_decode_carries_packet_metadata() {
+        let mut dec = Decoder::new(&h264(), &DecoderConfig::default()).unwrap();
+        let config = EncoderConfig {
+            max_b_frames: 2,
+            ..test_config()
+        };
+
+        let (mut packets, _) = encode_stream(&config, 10);
+        // With B-frames the packets come in decode order, so each frame must get the metadata
+        // of its own packet and not the last one fed in.
+        assert!(packets.iter().any(|p| p.pts != p.dts));
+        for packet in &mut packets {
+            packet.metadata = Some(Arc::new(packet.pts));
+            packet.rotation = (packet.pts as usize % 4) * 90;
         }
+
+        let frames = decode_frames(&mut dec, packets);
+        assert_eq!(frames.len(), 10);
+        for frame in &frames {
+            let metadata = frame.metadata().unwrap();
+            assert_eq!(metadata.downcast_ref::<i64>(), Some(&frame.pts()));
+            assert_eq!(frame.rotation(), (frame.pts() as usize % 4) * 90);
+        }
+    }
+
+    #[test]
+    fn test_transcode_with_motion_vectors() {
+        let config = DecoderConfig {
+            export_motion_vectors: true,
+            ..Default::default()
+        };
+        let mut dec = Decoder::new(&h264(), &config).unwrap();
+        let mut enc = Encoder::new(&x264(), &test_config()).unwrap();
+        let mut enc_borrowed = Encoder::new(&x264(), &test_config()).unwrap();
+
+        let (packets, _) = encode_stream(&test_config(), 10);
+        let frames = decode_frames(&mut dec, packets);
+        assert!(frames.iter().any(|frame| frame
+            .side_data()
+            .iter()
+            .any(|sd| matches!(sd, FrameSideData::MotionVectors(_)))));
+
+        // The motion vectors can't be passed to the encoder, which skips them.
+        let mut count = 0;
+        for frame in frames {
+            count += enc_borrowed.encode_borrowed(&frame, false).unwrap().count();
+            count += enc.encode(frame, false).unwrap().count();
+        }
+        count += enc_borrowed.flush().unwrap().count();
+        count += enc.flush().unwrap().count();
+
+        assert_eq!(count, 20);
+    }
+
+    #[test]
+    fn test_max_ref_frames() {
+        let decode = |max_ref_frames| {
+            let config = DecoderConfig {
+                thread_count: 4,
+                thread_type: DecodeThreadType::Frame,
+                limits: DecoderLimits {
+                    max_ref_frames: Some(max_ref_frames),
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let mut dec = Decoder::new(&h264(), &config).unwrap();
+
+            let config = EncoderConfig {
+                options: vec![("x264-params".to_string(), "ref=3".to_string())],
+                ..test_config()
+            };
+            let (packets, _) = encode_stream(&config, 5);
+            decode_count(&mut dec, packets)
+        };
+
+        // x264-params is applied after the ultrafast preset, which would use a single reference
+        // frame.
+        assert_eq!(decode(3).unwrap(), 5);
+        assert!(matches!(
+            decode(2),
+            Err(Error::LimitExceeded("reference frames", 3, 2))
+        ));
+
+        // Only H.264 is checked, and other decoders keep their threading.
+        let mpeg4 = Codec::list(CodecKind::Decoder)
+            .find(|c| c.name() == "mpeg4")
+            .unwrap();
+        let config = DecoderConfig {
+            thread_type: DecodeThreadType::Frame,
+            limits: DecoderLimits {
+                max_ref_frames: Some(0),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let dec = Decoder::new(&mpeg4, &config).unwrap();
+        assert_eq!(dec.limits.max_ref_frames, None);
+        // SAFETY: The pointer is valid while dec is alive.
+        assert_eq!(
+            unsafe { (*dec.ctx).thread_type },
+            sys::FF_THREAD_FRAME as i32
+        );
+    }
+
+    /// Hands out plain vectors and keeps track of the ones still alive.
+    #[derive(Debug, Default)]
+    struct TrackingAllocator {
+        live: Arc<Mutex<Vec<Range<usize>>>>,
+    }
+
+    struct TrackedBuffer {
+        data: Vec<u8>,
+        live: Arc<Mutex<Vec<Range<usize>>>>,
+    }
+
+    impl FrameAllocator for TrackingAllocator {
+        fn allocate(&self, size: usize) -> Option<FrameBuffer> {
+            let data = vec![0; size];
+            let range = data.as_ptr_range();
+            let range = range.start as usize..range.end as usize;
+            self.live.lock().unwrap().push(range);
+
+            Some(Box::new(TrackedBuffer {
+                data,
+                live: self.live.clone(),
+            }))
+        }
+    }
+
+    impl AsMut<[u8]> for TrackedBuffer {
+        fn as_mut(&mut self) -> &mut [u8] {
+            &mut self.data
+        }
+    }
+
+    impl Drop for TrackedBuffer {
+        fn drop(&mut self) {
+            let start = self.data.as_ptr() as usize;
+            self.live
+                .lock()
+                .unwrap()
+                .retain(|range| range.start != start);
+        }
+    }
+
+    #[test]
+    fn test_decode_into_allocator() {
+        let allocator = TrackingAllocator::default();
+        let live = allocator.live.clone();
+        let config = DecoderConfig {
+            allocator: Some(SharedAllocator::new(allocator)),
+            ..Default::default()
+        };
+        assert_eq!(config, config.clone());
+        let mut dec = Decoder::new(&h264(), &config).unwrap();
+
+        let (packets, _) = encode_stream(&test_config(), 10);
+        let frames = decode_frames(&mut dec, packets);
+        assert_eq!(frames.len(), 10);
+        for frame in &frames {
+            let live = live.lock().unwrap();
+            for i in 0..frame.plane_count() {
+                let plane = frame.get_plane(i).as_ptr_range();
+                let (start, end) = (plane.start as usize, plane.end as usize);
+                assert!(live.iter().any(|r| r.start <= start && end <= r.end));
+            }
+        }
+
+        drop(frames);
+        drop(dec);
+        assert!(live.lock().unwrap().is_empty());
+    }
+
+    #[derive(Debug)]
+    struct PanickingAllocator;
+
+    impl FrameAllocator for PanickingAllocator {
+        fn allocate(&self, _size: usize) -> Option<FrameBuffer> {
+            panic!("out of frame buffers");
+        }
+    }
+
+    #[test]
+    fn test_panicking_allocator() {
+        let config = DecoderConfig {
+            thread_count: 1,
+            allocator: Some(SharedAllocator::new(PanickingAllocator)),
+            ..Default::default()
+        };
+        let mut dec = Decoder::new(&h264(), &config).unwrap();
+
+        // The panic fails the decode rather than unwinding through libavcodec.
+        let (packets, _) = encode_stream(&test_config(), 1);
+        assert!(decode_count(&mut dec, packets).is_err());
+    }
+
+    #[test]
+    fn test_decode_with_extradata() {
+        // The parameter sets only go to the extradata, not in-band.
+        let config = EncoderConfig {
+            global_header: true,
+            ..test_config()
+        };
+        let (packets, extradata) = encode_stream(&config, 10);
+        assert!(!extradata.is_empty());
+
+        let config = DecoderConfig {
+            extradata,
+            ..Default::default()
+        };
+        let mut dec = Decoder::new(&h264(), &config).unwrap();
+        assert_eq!(decode_count(&mut dec, packets).unwrap(), 10);
+    }
+
+    #[test]
+    fn test_recover_from_damaged_packet() {
+        let config = EncoderConfig {
+            keyframe_distance: 10,
+            ..test_config()
+        };
+        let mut enc = Encoder::new(&x264(), &config).unwrap();
+        let mut packets = vec![];
+        for pts in 0..20 {
+            // Noise, so that every packet carries enough slice data to damage.
+            let mut frame = test_frame(64, 48, pts);
+            let mut seed = pts as u32 + 1;
+            for px in frame.plane_mut(0) {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                *px = (seed >> 16) as u8;
+            }
+            packets.extend(enc.encode(frame, false).unwrap().map(TestPacket::new));
+        }
+        packets.extend(flush(&mut enc));
+        assert_eq!(packets.len(), 20);
+        assert!(packets[10].keyframe);
+
+        // Lose the second half of a packet in the middle of the first GOP.
+        let len = packets[3].data.0.len() - sys::AV_INPUT_BUFFER_PADDING_SIZE as usize;
+        packets[3].data = PaddedDataImpl::from(&packets[3].data.0[..len / 2]);
+
+        let config = DecoderConfig {
+            thread_count: 1,
+            output_corrupt: true,
+            ..Default::default()
+        };
+        let mut dec = Decoder::new(&h264(), &config).unwrap();
+
+        let mut corrupt = vec![];
+        for (i, packet) in packets.into_iter().enumerate() {
+            if let Ok(frames) = dec.decode(packet) {
+                for frame in frames.flatten() {
+                    if frame.is_corrupt() {
+                        corrupt.push(frame.pts());
+                    }
+                }
+            }
+            assert_eq!(dec.needs_keyframe(), (3..10).contains(&i), "packet {i}");
+        }
+        assert!(corrupt.contains(&3));
+        assert!(corrupt.iter().all(|pts| (3..10).contains(pts)));
+
+        // Seeking starts over, so the old damage is forgotten.
+        dec.needs_keyframe = true;
+        dec.reset();
+        assert!(!dec.needs_keyframe());
+    }
+
+    #[test]
+    fn test_limits() {
+        let limits = DecoderLimits {
+            max_width: Some(1920),
+            max_height: Some(1080),
+            max_pixels: Some(1280 * 720),
+            max_thread_count: Some(4),
+            ..Default::default()
+        };
+
+        assert_eq!(limits.max_pixels(), Some(1280 * 720));
+        assert_eq!(limits.thread_count(0), 4);
+        assert_eq!(limits.thread_count(2), 2);
+        assert!(limits.check_size(1280, 720).is_ok());
+        assert!(matches!(
+            limits.check_size(1920, 1080),
+            Err(Error::LimitExceeded("pixels", _, _))
+        ));
+        assert!(matches!(
+            limits.check_size(4000, 10),
+            Err(Error::LimitExceeded("width", 4000, 1920))
+        ));
     }
 }
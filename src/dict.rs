@@ -0,0 +1,66 @@
+use std::ffi::{CStr, CString};
+use std::ptr;
+
+use super::{sys, Error};
+
+/// An owned `AVDictionary`, used to pass options to libavcodec.
+pub(crate) struct Dictionary(*mut sys::AVDictionary);
+
+impl Dictionary {
+    /// Build a dictionary from key/value pairs. Later entries overwrite earlier ones with the same
+    /// key.
+    pub(crate) fn new(entries: &[(String, String)]) -> Result<Self, Error> {
+        let mut dict = Dictionary(ptr::null_mut());
+
+        for (key, value) in entries {
+            let k = CString::new(key.as_str()).map_err(|_| Error::InvalidOption(key.clone()))?;
+            let v = CString::new(value.as_str()).map_err(|_| Error::InvalidOption(key.clone()))?;
+
+            let err = unsafe { sys::av_dict_set(&mut dict.0, k.as_ptr(), v.as_ptr(), 0) };
+            if err < 0 {
+                return Err(Error::AlllocateFailed("av_dict_set for Dictionary::new"));
+            }
+        }
+
+        Ok(dict)
+    }
+
+    /// Pointer suitable for functions like `avcodec_open2` which consume entries they recognise.
+    pub(crate) fn as_mut_ptr(&mut self) -> *mut *mut sys::AVDictionary {
+        &mut self.0
+    }
+
+    /// The keys currently in the dictionary.
+    pub(crate) fn keys(&self) -> Vec<String> {
+        let mut keys = vec![];
+        let mut entry: *mut sys::AVDictionaryEntry = ptr::null_mut();
+
+        loop {
+            // SAFETY: The dictionary is valid (or null, which is allowed) while self is alive.
+            entry = unsafe {
+                sys::av_dict_get(
+                    self.0,
+                    c"".as_ptr(),
+                    entry,
+                    sys::AV_DICT_IGNORE_SUFFIX as i32,
+                )
+            };
+            if entry.is_null() {
+                break;
+            }
+
+            let key = unsafe { CStr::from_ptr((*entry).key) };
+            keys.push(key.to_string_lossy().to_string());
+        }
+
+        keys
+    }
+}
+
+impl Drop for Dictionary {
+    fn drop(&mut self) {
+        unsafe {
+            sys::av_dict_free(&mut self.0);
+        }
+    }
+}
@@ -1,30 +1,99 @@
+use std::collections::VecDeque;
 use std::ffi::c_void;
 use std::ffi::CStr;
 use std::ptr;
 
-use tracing::Level;
+use tracing::{trace, Level};
 
+use crate::dict::Dictionary;
+use crate::frame_pool::FramePool;
+use crate::opaque::Opaque;
+use crate::validate_frame;
 use crate::Packet;
 use crate::MAX_PLANES;
 
-use super::sys::AVPixelFormat as PixelFormat;
 use super::{av_log_set_callback, err_code_to_string, log_callback, set_log_level};
-use super::{sys, Codec, CodecKind, Error, Frame};
+use super::{
+    sys, Codec, CodecKind, Error, Frame, FrameSideData, Metadata, PacketSideData, PixelFormat,
+    Rational,
+};
 
 pub struct Encoder {
     codec: *const sys::AVCodec,
     ctx: *mut sys::AVCodecContext,
+    config: EncoderConfig,
+    /// Rotation of the last frame passed to encode, used for packets from codecs that can't
+    /// carry the frame opaque_ref.
+    last_rotation: usize,
+    /// Packets drained from a replaced context, handed out before any new ones.
+    pending: VecDeque<EncodedPacket>,
+    /// Set when the context was re-opened for a new input size, until the first packet of the
+    /// new context is handed out.
+    resolution_change: Option<(usize, usize)>,
+    /// Set when a re-opened context came with different extradata, until the first packet of
+    /// the new context carries it as side data.
+    new_extradata: bool,
+    /// Frames for [`Encoder::encode_borrowed`] to copy into, created on first use.
+    pool: Option<FramePool>,
+    /// Set by flush, the context has to be reset before it accepts frames again.
+    drained: bool,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct EncoderConfig {
-    pub bitrate: u32,
+    pub rate_control: RateControl,
+    /// Lowest quantizer the rate control may use. `None` leaves the codec default.
+    pub min_qp: Option<u32>,
+    /// Highest quantizer the rate control may use. `None` leaves the codec default.
+    pub max_qp: Option<u32>,
     pub width: u32,
     pub height: u32,
-    pub fps: u8,
+    /// Time base of the frame pts, e.g. 1/90000 for the RTP clock. Packets come out with pts in
+    /// this time base too.
+    pub time_base: Rational,
+    /// Nominal frame rate, used by the rate control. The actual frame timing follows the pts.
+    pub framerate: Rational,
     pub thread_count: u32,
     pub max_b_frames: u32,
     pub keyframe_distance: u32,
+    /// Put the parameter sets (SPS/PPS, VPS, AV1 sequence header OBUs) in
+    /// [`Encoder::extradata`] instead of in the bitstream, as MP4-style containers expect.
+    pub global_header: bool,
+    /// With `global_header`, also repeat the parameter sets in-band on every keyframe, for
+    /// encoders that support it, like libx264. For libx264 it's added to any `x264-params` in
+    /// `options`.
+    pub repeat_headers: bool,
+    /// Compute the error of each plane, reported in [`QualityStats::errors`](crate::QualityStats).
+    pub psnr: bool,
+    /// Pixel format of the frames that will be passed to [`Encoder::encode`].
+    pub pixel_format: PixelFormat,
+    /// Extra AVOptions passed to the codec when opening it, e.g. `("preset", "veryfast")`.
+    ///
+    /// These are applied after the defaults this crate picks for the codec, so they can be used
+    /// to override them. Opening fails if the codec doesn't recognise any of them.
+    pub options: Vec<(String, String)>,
+}
+
+/// How the encoder trades bitrate for quality.
+///
+/// Bitrates are in bits per second.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateControl {
+    /// Constant bitrate, constrained by a VBV buffer of `buffer_size` bits.
+    Cbr { bitrate: u32, buffer_size: u32 },
+    /// Variable bitrate averaging `bitrate`, optionally never exceeding `max_bitrate`.
+    Vbr {
+        bitrate: u32,
+        max_bitrate: Option<u32>,
+    },
+    /// Constant quality, i.e. `crf` for libx264, libvpx and libaom, `cq` for nvenc. Lower is
+    /// better. The bitrate can optionally be capped with `max_bitrate`.
+    ConstantQuality {
+        level: u32,
+        max_bitrate: Option<u32>,
+    },
+    /// Fixed quantizer for every frame.
+    ConstantQp { qp: u32 },
 }
 
 impl Encoder {
@@ -38,73 +107,19 @@ impl Encoder {
             }
 
             let codec = codec.ptr;
+            let ctx = open_context(codec, config)?;
 
-            let ctx: *mut sys::AVCodecContext = sys::avcodec_alloc_context3(codec);
-            if ctx.is_null() {
-                return Err(Error::CreateContextFailed);
-            }
-
-            let enc = Encoder { codec, ctx };
-
-            {
-                (*ctx).bit_rate = config.bitrate as i64;
-                (*ctx).width = config.width as i32;
-                (*ctx).height = config.height as i32;
-                (*ctx).time_base = sys::AVRational {
-                    num: 1,
-                    den: config.fps as i32,
-                };
-                (*ctx).framerate = sys::AVRational {
-                    num: config.fps as i32,
-                    den: 1,
-                };
-                (*ctx).pix_fmt = PixelFormat::AV_PIX_FMT_YUV420P;
-                (*ctx).thread_count = config.thread_count as i32;
-                (*ctx).max_b_frames = config.max_b_frames as i32;
-                (*ctx).gop_size = config.keyframe_distance as i32;
-                (*ctx).flags = sys::AV_CODEC_FLAG_LOW_DELAY as i32;
-                (*ctx).flags2 = sys::AV_CODEC_FLAG2_FAST as i32;
-            }
-
-            let is_nvidia = (*codec).name == c"h264_nvenc".as_ptr();
-            let is_x264 = (*codec).id == sys::AVCodecID::AV_CODEC_ID_H264;
-            let is_vpx =
-                (*codec).name == c"libvpx".as_ptr() || (*codec).name == c"libvpx-vp9".as_ptr();
-
-            if is_nvidia {
-                const OPTS: &[(&CStr, &CStr)] = &[
-                    (c"preset", c"llhp"),
-                    (c"rc", c"vbr"),
-                    (c"profile", c"baseline"),
-                ];
-                for (k, v) in OPTS {
-                    // This sets options directly on nvidia
-                    sys::av_opt_set((*ctx).priv_data, k.as_ptr(), v.as_ptr(), 0);
-                }
-            } else if is_x264 {
-                // To be WebRTC compatible
-                (*ctx).profile = sys::FF_PROFILE_H264_CONSTRAINED_BASELINE as i32;
-
-                const OPTS: &[(&CStr, &CStr)] = &[
-                    //
-                    (c"preset", c"ultrafast"),
-                    (c"tune", c"zerolatency"),
-                ];
-                for (k, v) in OPTS {
-                    // This sets options directly on libx264
-                    sys::av_opt_set((*ctx).priv_data, k.as_ptr(), v.as_ptr(), 0);
-                }
-            } else if is_vpx {
-                // This sets options directly on libvpx
-                sys::av_opt_set((*ctx).priv_data, c"lag_in_frames".as_ptr(), &0, 0);
-            }
-
-            let err = sys::avcodec_open2(ctx, codec, ptr::null_mut());
-            if err < 0 {
-                return Err(Error::CodecOpenError(err, err_code_to_string(err)));
-            }
-
-            Ok(enc)
+            Ok(Encoder {
+                codec,
+                ctx,
+                config: config.clone(),
+                last_rotation: 0,
+                pending: VecDeque::new(),
+                resolution_change: None,
+                new_extradata: false,
+                pool: None,
+                drained: false,
+            })
         }
     }
 
@@ -120,12 +135,83 @@ impl Encoder {
         unsafe { Codec::from_ptr(self.codec) }
     }
 
+    /// The codec configuration, e.g. an `avcC`/`hvcC` style record or the parameter sets in
+    /// Annex B format, depending on the codec.
+    ///
+    /// Only set with [`EncoderConfig::global_header`]. It changes when the encoder is re-opened,
+    /// e.g. for a new input size, in which case the first packet from the new context carries
+    /// it as [`PacketSideData::NewExtradata`].
+    pub fn extradata(&self) -> &[u8] {
+        // SAFETY: The pointer is valid while self is alive, and extradata_size is its length.
+        unsafe {
+            let size = (*self.ctx).extradata_size;
+            if (*self.ctx).extradata.is_null() || size <= 0 {
+                return &[];
+            }
+
+            std::slice::from_raw_parts((*self.ctx).extradata, size as usize)
+        }
+    }
+
+    /// Encode a frame.
+    ///
+    /// The frame must be in the configured pixel format, and its planes must hold a complete
+    /// image as described by its size and strides, otherwise this fails without touching the
+    /// encoder.
+    ///
+    /// If the frame size differs from the size the encoder is configured for, the encoder is
+    /// drained and re-opened with the new size. The first packet from the new context is a
+    /// keyframe and reports the new size in [`Packet::resolution_change`].
     pub fn encode<T: Frame>(
         &mut self,
         frame: T,
         force_keyframe: bool,
     ) -> Result<impl Iterator<Item = Result<impl Packet<[u8]>, Error>> + '_, Error> {
-        let pts = frame.pts();
+        validate_frame(&frame, self.config.pixel_format)?;
+
+        // Most encoders silently ignore regions they can't apply. Checked before a flushed or
+        // resized encoder is re-opened, so a rejected frame leaves the encoder as it was.
+        let has_roi = frame
+            .side_data()
+            .iter()
+            .any(|side_data| matches!(side_data, FrameSideData::RegionsOfInterest(_)));
+        if has_roi && !self.supports_roi() {
+            return Err(Error::RoiNotSupported(self.codec().name()));
+        }
+
+        if self.drained {
+            // Hand out whatever the caller didn't take from the flush iterator before starting
+            // over.
+            self.drain_into_pending()?;
+            self.reset()?;
+        }
+
+        let pts = match frame.time_base() {
+            Some(time_base) if time_base != self.config.time_base => {
+                time_base.rescale(frame.pts(), self.config.time_base)
+            }
+            _ => frame.pts(),
+        };
+        // One frame at the nominal rate, which the encoder copies to the packet.
+        let duration = match self.config.framerate {
+            Rational { num: 0, .. } => 0,
+            framerate => {
+                Rational::new(framerate.den, framerate.num).rescale(1, self.config.time_base)
+            }
+        };
+
+        let resized = frame.width() != self.config.width as usize
+            || frame.height() != self.config.height as usize;
+        if resized {
+            // An open context can't change size, so start over with a new one.
+            let config = EncoderConfig {
+                width: frame.width() as u32,
+                height: frame.height() as u32,
+                ..self.config.clone()
+            };
+            self.reopen_with(config)?;
+            self.resolution_change = Some((frame.width(), frame.height()));
+        }
 
         let mut fr = unsafe { sys::av_frame_alloc() };
 
@@ -138,19 +224,41 @@ impl Encoder {
             strides[i] = frame.get_stride(i) as i32;
         }
 
+        let format = frame.pixel_format();
         let width = frame.width() as i32;
         let height = frame.height() as i32;
 
         let rotation = frame.rotation();
-        let pic_type = if force_keyframe {
+        self.last_rotation = rotation;
+        let opaque_data = Opaque {
+            rotation,
+            metadata: frame.metadata(),
+        };
+        let pic_type = if force_keyframe || resized {
             sys::AVPictureType::AV_PICTURE_TYPE_I
         } else {
             sys::AVPictureType::AV_PICTURE_TYPE_NONE
         };
         unsafe { (*fr).pict_type = pic_type };
 
+        // Copy the side data now, `frame` may be moved below.
+        for side_data in frame.side_data() {
+            if let Err(e) = skip_unsupported(unsafe { side_data.add_to(fr) }) {
+                unsafe { sys::av_frame_free(&mut fr) };
+                return Err(e);
+            }
+        }
+
         let buffers = if let Some(buffers) = frame.as_avcodec_buf_ref() {
-            buffers
+            // The buffers are unreferenced when `fr` is freed below, so take our own references
+            // and leave the ones held by `frame` alone.
+            buffers.map(|buf| {
+                if buf.is_null() {
+                    buf
+                } else {
+                    unsafe { sys::av_buffer_ref(buf) }
+                }
+            })
         } else {
             let droppable = frame.into_droppable();
             let boxed = Box::new(droppable);
@@ -171,10 +279,13 @@ impl Encoder {
         };
 
         unsafe {
-            (*fr).format = PixelFormat::AV_PIX_FMT_YUV420P as i32;
+            (*fr).format = format.as_av() as i32;
             (*fr).width = width;
             (*fr).height = height;
             (*fr).pts = pts;
+            (*fr).duration = duration;
+            // With AV_CODEC_FLAG_COPY_OPAQUE this ends up on the packet encoded from the frame.
+            (*fr).opaque_ref = opaque_data.into_buffer();
             (*fr).data = planes;
             (*fr).linesize = strides;
             (*fr).buf = buffers;
@@ -190,11 +301,484 @@ impl Encoder {
             return Err(Error::EncodeFrameFailed(ret, err_code_to_string(ret)));
         }
 
-        Ok(PacketIterator {
-            enc: Some(self),
-            rotation,
-        })
+        Ok(PacketIterator { enc: Some(self) })
+    }
+
+    /// Encode a frame the caller keeps ownership of.
+    ///
+    /// The planes are copied into a frame from a pool owned by the encoder, so `frame` can be
+    /// reused as soon as this returns. Pooled frames are recycled once the encoder is done with
+    /// them, which avoids allocating for every frame. Otherwise this behaves like
+    /// [`Encoder::encode`].
+    pub fn encode_borrowed<T: Frame>(
+        &mut self,
+        frame: &T,
+        force_keyframe: bool,
+    ) -> Result<impl Iterator<Item = Result<impl Packet<[u8]>, Error>> + '_, Error> {
+        validate_frame(frame, self.config.pixel_format)?;
+
+        let format = frame.pixel_format();
+        let (width, height) = (frame.width(), frame.height());
+        let pool = match self.pool.take() {
+            Some(pool) if pool.matches(format, width, height) => pool,
+            _ => FramePool::new(format, width, height)?,
+        };
+        let copy = pool.get();
+        self.pool = Some(pool);
+        let mut copy = copy?;
+
+        for i in 0..format.plane_count() {
+            let stride = copy.get_stride(i);
+            let plane = copy.plane_mut(i);
+            unsafe {
+                sys::av_image_copy_plane(
+                    plane.as_mut_ptr(),
+                    stride as i32,
+                    frame.get_plane(i).as_ptr(),
+                    frame.get_stride(i) as i32,
+                    format.line_size(i, width) as i32,
+                    format.plane_height(i, height) as i32,
+                );
+            }
+        }
+
+        copy.set_pts(frame.pts());
+        copy.set_time_base(frame.time_base());
+        copy.set_rotation(frame.rotation());
+        copy.set_metadata(frame.metadata());
+        for side_data in frame.side_data() {
+            skip_unsupported(copy.add_side_data(&side_data))?;
+        }
+
+        self.encode(copy, force_keyframe)
+    }
+
+    /// Signal end of stream to the encoder.
+    ///
+    /// Returns an iterator over the packets still buffered in the encoder (B-frames, lookahead,
+    /// frame threads). The next call to [`Encoder::encode`] resets the encoder to start a new
+    /// stream, any packets not taken from the iterator are returned by that call.
+    pub fn flush(
+        &mut self,
+    ) -> Result<impl Iterator<Item = Result<impl Packet<[u8]>, Error>> + '_, Error> {
+        let ret = unsafe { sys::avcodec_send_frame(self.ctx, ptr::null()) };
+
+        // AVERROR_EOF means we are already draining, which is fine.
+        if ret < 0 && ret != sys::AVErrorEof {
+            return Err(Error::EncodeFrameFailed(ret, err_code_to_string(ret)));
+        }
+        self.drained = true;
+
+        Ok(PacketIterator { enc: Some(self) })
+    }
+
+    /// Change the target bitrate of a live encoder.
+    ///
+    /// libx264 and libvpx pick up the new bitrate from the next frame. Other codecs are
+    /// transparently re-opened, which means the next frame will be a keyframe. Packets still
+    /// buffered in the old context are returned by the next call to `encode`.
+    ///
+    /// Only valid for [`RateControl::Cbr`] and [`RateControl::Vbr`].
+    pub fn set_bitrate(&mut self, bitrate: u32) -> Result<(), Error> {
+        let mut config = self.config.clone();
+        match &mut config.rate_control {
+            RateControl::Cbr { bitrate: b, .. } | RateControl::Vbr { bitrate: b, .. } => {
+                *b = bitrate;
+            }
+            rc => return Err(Error::BitrateNotSupported(*rc)),
+        }
+
+        if self.can_reconfigure() {
+            // SAFETY: The context is valid while self is alive, and can_reconfigure means this
+            // isn't nvenc.
+            unsafe { set_rate_control(self.ctx, false, &config)? };
+            self.config = config;
+            Ok(())
+        } else {
+            self.reopen_with(config)
+        }
+    }
+
+    /// Change the framerate of a live encoder.
+    ///
+    /// No codec picks up a new framerate on an open context, so the encoder is always re-opened
+    /// as described in [`Encoder::set_bitrate`]. The time base is left as is, so pts keep their
+    /// meaning.
+    pub fn set_framerate(&mut self, framerate: Rational) -> Result<(), Error> {
+        let config = EncoderConfig {
+            framerate,
+            ..self.config.clone()
+        };
+        self.reopen_with(config)
+    }
+
+    /// Whether the codec picks up rate control changes on an open context.
+    fn can_reconfigure(&self) -> bool {
+        matches!(
+            self.codec().name(),
+            "libx264" | "libx264rgb" | "libvpx" | "libvpx-vp9"
+        )
+    }
+
+    /// Whether the codec applies `AV_FRAME_DATA_REGIONS_OF_INTEREST`.
+    fn supports_roi(&self) -> bool {
+        match self.codec().name() {
+            // libx264 skips the regions when adaptive quantization is off.
+            "libx264" | "libx264rgb" => self.x264_aq_enabled(),
+            "libx265" | "libvpx" | "libvpx-vp9" => true,
+            _ => false,
+        }
+    }
+
+    /// Whether libx264 runs with adaptive quantization.
+    fn x264_aq_enabled(&self) -> bool {
+        let option = |name: &str| {
+            self.config
+                .options
+                .iter()
+                .rev()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.as_str())
+        };
+
+        // libx264 applies x264-params last, after x264opts and after its own aq-mode option.
+        for name in ["x264-params", "x264opts"] {
+            let aq_mode = option(name).and_then(|params| {
+                params
+                    .split(':')
+                    .rev()
+                    .filter_map(|param| param.split_once('='))
+                    .filter(|(key, _)| key.replace('_', "-") == "aq-mode")
+                    .find_map(|(_, value)| value.parse::<i64>().ok())
+            });
+            if let Some(aq_mode) = aq_mode {
+                return aq_mode > 0;
+            }
+        }
+
+        let mut aq_mode = -1;
+        // SAFETY: The context is valid while self is alive, and libx264 has private options.
+        let ret = unsafe {
+            sys::av_opt_get_int((*self.ctx).priv_data, c"aq-mode".as_ptr(), 0, &mut aq_mode)
+        };
+        if ret >= 0 && aq_mode >= 0 {
+            return aq_mode > 0;
+        }
+
+        // Left to x264, where every preset but ultrafast and every tune but psnr enable it.
+        option("preset").is_some_and(|preset| preset != "ultrafast")
+            && !option("tune").is_some_and(|tune| tune.contains("psnr"))
+    }
+
+    /// Re-open with `config`, keeping the current config if that fails.
+    fn reopen_with(&mut self, config: EncoderConfig) -> Result<(), Error> {
+        let old_config = std::mem::replace(&mut self.config, config);
+        let res = self.reopen();
+        if res.is_err() {
+            self.config = old_config;
+        }
+        res
+    }
+
+    /// Drain the current context into `pending` and replace it with one using the current config.
+    fn reopen(&mut self) -> Result<(), Error> {
+        self.drain_into_pending()?;
+        self.replace_context()
+    }
+
+    /// Drain the current context, keeping the packets in `pending`.
+    fn drain_into_pending(&mut self) -> Result<(), Error> {
+        let ret = unsafe { sys::avcodec_send_frame(self.ctx, ptr::null()) };
+        if ret < 0 && ret != sys::AVErrorEof {
+            return Err(Error::EncodeFrameFailed(ret, err_code_to_string(ret)));
+        }
+
+        loop {
+            let mut pkt = unsafe { sys::av_packet_alloc() };
+            let ret = unsafe { sys::avcodec_receive_packet(self.ctx, pkt) };
+            if ret < 0 {
+                unsafe { sys::av_packet_free(&mut pkt) };
+                if ret == sys::AVErrorEof {
+                    break;
+                }
+                return Err(Error::ReceivePacketFailed(ret, err_code_to_string(ret)));
+            }
+            unsafe { (*pkt).time_base = (*self.ctx).time_base };
+
+            self.pending.push_back(EncodedPacket {
+                pkt,
+                rotation: self.last_rotation,
+                resolution_change: None,
+            });
+        }
+        self.drained = true;
+
+        Ok(())
+    }
+
+    /// Make a drained encoder accept frames again.
+    fn reset(&mut self) -> Result<(), Error> {
+        let capabilities = unsafe { (*self.codec).capabilities };
+
+        if capabilities & sys::AV_CODEC_CAP_ENCODER_FLUSH as i32 > 0 {
+            unsafe { sys::avcodec_flush_buffers(self.ctx) };
+            self.drained = false;
+            return Ok(());
+        }
+
+        // avcodec_flush_buffers is a no-op for encoders without AV_CODEC_CAP_ENCODER_FLUSH,
+        // and a drained context can't be restarted, so we replace it with a fresh one.
+        self.replace_context()
+    }
+
+    fn replace_context(&mut self) -> Result<(), Error> {
+        let ctx = open_context(self.codec, &self.config)?;
+        let old_extradata = self.extradata().to_vec();
+        unsafe {
+            sys::avcodec_free_context(&mut self.ctx);
+        }
+        self.ctx = ctx;
+        self.drained = false;
+        self.new_extradata |= self.extradata() != old_extradata;
+
+        Ok(())
+    }
+}
+
+/// Allocate and open a codec context for `codec` configured from `config`.
+fn open_context(
+    codec: *const sys::AVCodec,
+    config: &EncoderConfig,
+) -> Result<*mut sys::AVCodecContext, Error> {
+    unsafe {
+        let mut ctx: *mut sys::AVCodecContext = sys::avcodec_alloc_context3(codec);
+        if ctx.is_null() {
+            return Err(Error::CreateContextFailed);
+        }
+        let mut options = config.options.clone();
+
+        {
+            (*ctx).width = config.width as i32;
+            (*ctx).height = config.height as i32;
+            (*ctx).time_base = config.time_base.into();
+            (*ctx).framerate = config.framerate.into();
+            (*ctx).pix_fmt = config.pixel_format.as_av();
+            (*ctx).thread_count = config.thread_count as i32;
+            (*ctx).max_b_frames = config.max_b_frames as i32;
+            (*ctx).gop_size = config.keyframe_distance as i32;
+            (*ctx).flags = sys::AV_CODEC_FLAG_LOW_DELAY as i32;
+            (*ctx).flags2 = sys::AV_CODEC_FLAG2_FAST as i32;
+            if config.psnr {
+                (*ctx).flags |= sys::AV_CODEC_FLAG_PSNR as i32;
+            }
+            if config.global_header {
+                (*ctx).flags |= sys::AV_CODEC_FLAG_GLOBAL_HEADER as i32;
+                if config.repeat_headers {
+                    // libx264 ignores this, see below.
+                    (*ctx).flags2 |= sys::AV_CODEC_FLAG2_LOCAL_HEADER as i32;
+                }
+            }
+
+            // Carry the frame opaque_ref over to the encoded packets. Delaying encoders that
+            // can't do this refuse to open with the flag set.
+            let capabilities = (*codec).capabilities;
+            if capabilities & sys::AV_CODEC_CAP_ENCODER_REORDERED_OPAQUE as i32 > 0
+                || capabilities & sys::AV_CODEC_CAP_DELAY as i32 == 0
+            {
+                (*ctx).flags |= sys::AV_CODEC_FLAG_COPY_OPAQUE as i32;
+            }
+        }
+
+        let name = Codec::from_ptr(codec).name();
+        let is_nvidia = name.ends_with("_nvenc");
+        let is_x264 = (*codec).id == sys::AVCodecID::AV_CODEC_ID_H264;
+        let is_vpx = name == "libvpx" || name == "libvpx-vp9";
+
+        if is_nvidia {
+            const OPTS: &[(&CStr, &CStr)] = &[(c"preset", c"llhp"), (c"rc", c"vbr")];
+            for (k, v) in OPTS {
+                // This sets options directly on nvidia
+                sys::av_opt_set((*ctx).priv_data, k.as_ptr(), v.as_ptr(), 0);
+            }
+            if name == "h264_nvenc" {
+                sys::av_opt_set(
+                    (*ctx).priv_data,
+                    c"profile".as_ptr(),
+                    c"baseline".as_ptr(),
+                    0,
+                );
+            }
+        } else if is_x264 {
+            // To be WebRTC compatible
+            (*ctx).profile = sys::FF_PROFILE_H264_CONSTRAINED_BASELINE as i32;
+
+            const OPTS: &[(&CStr, &CStr)] = &[
+                //
+                (c"preset", c"ultrafast"),
+                (c"tune", c"zerolatency"),
+            ];
+            for (k, v) in OPTS {
+                // This sets options directly on libx264
+                sys::av_opt_set((*ctx).priv_data, k.as_ptr(), v.as_ptr(), 0);
+            }
+            if config.global_header && config.repeat_headers {
+                // A user supplied x264-params replaces this one when the codec is opened, so add
+                // to theirs instead.
+                match options.iter_mut().rev().find(|(k, _)| k == "x264-params") {
+                    Some((_, params)) => {
+                        if !params.is_empty() {
+                            params.push(':');
+                        }
+                        params.push_str("repeat-headers=1");
+                    }
+                    None => {
+                        sys::av_opt_set(
+                            (*ctx).priv_data,
+                            c"x264-params".as_ptr(),
+                            c"repeat-headers=1".as_ptr(),
+                            0,
+                        );
+                    }
+                }
+            }
+        } else if is_vpx {
+            // This sets options directly on libvpx
+            sys::av_opt_set((*ctx).priv_data, c"lag_in_frames".as_ptr(), &0, 0);
+        }
+
+        if let Err(e) = set_rate_control(ctx, is_nvidia, config) {
+            sys::avcodec_free_context(&mut ctx);
+            return Err(e);
+        }
+
+        let mut options = match Dictionary::new(&options) {
+            Ok(v) => v,
+            Err(e) => {
+                sys::avcodec_free_context(&mut ctx);
+                return Err(e);
+            }
+        };
+
+        let err = sys::avcodec_open2(ctx, codec, options.as_mut_ptr());
+        if err < 0 {
+            sys::avcodec_free_context(&mut ctx);
+            return Err(Error::CodecOpenError(err, err_code_to_string(err)));
+        }
+
+        // avcodec_open2 removes the options it consumed, anything left is unknown to the codec.
+        let unused = options.keys();
+        if !unused.is_empty() {
+            sys::avcodec_free_context(&mut ctx);
+            return Err(Error::UnusedOptions(unused));
+        }
+
+        Ok(ctx)
+    }
+}
+
+/// Skip side data no encoder takes, e.g. motion vectors on frames from a decoder, instead of
+/// failing the encode.
+fn skip_unsupported(res: Result<(), Error>) -> Result<(), Error> {
+    match res {
+        Err(Error::UnsupportedSideData(kind)) => {
+            trace!("Skipping frame side data of type {kind} when encoding");
+            Ok(())
+        }
+        res => res,
+    }
+}
+
+/// Map the rate control settings to the generic context fields and the codec private options.
+///
+/// Must be called after the codec defaults are set, since it overrides some of them. Fails if
+/// the codec lacks the private option the rate control mode needs.
+unsafe fn set_rate_control(
+    ctx: *mut sys::AVCodecContext,
+    is_nvidia: bool,
+    config: &EncoderConfig,
+) -> Result<(), Error> {
+    let priv_data = (*ctx).priv_data;
+
+    match config.rate_control {
+        RateControl::Cbr {
+            bitrate,
+            buffer_size,
+        } => {
+            // libvpx switches to CBR when min, max and target rate are equal.
+            (*ctx).bit_rate = bitrate as i64;
+            (*ctx).rc_min_rate = bitrate as i64;
+            (*ctx).rc_max_rate = bitrate as i64;
+            (*ctx).rc_buffer_size = buffer_size as i32;
+
+            if is_nvidia {
+                sys::av_opt_set(priv_data, c"rc".as_ptr(), c"cbr".as_ptr(), 0);
+            }
+        }
+        RateControl::Vbr {
+            bitrate,
+            max_bitrate,
+        } => {
+            (*ctx).bit_rate = bitrate as i64;
+            if let Some(max_bitrate) = max_bitrate {
+                set_max_bitrate(ctx, max_bitrate);
+            }
+
+            if is_nvidia {
+                sys::av_opt_set(priv_data, c"rc".as_ptr(), c"vbr".as_ptr(), 0);
+            }
+        }
+        RateControl::ConstantQuality { level, max_bitrate } => {
+            // Without a target bitrate libx264 and libvpx-vp9 run in pure quality mode.
+            (*ctx).bit_rate = 0;
+            if let Some(max_bitrate) = max_bitrate {
+                // libvpx treats the bitrate as the cap in constrained quality mode.
+                (*ctx).bit_rate = max_bitrate as i64;
+                set_max_bitrate(ctx, max_bitrate);
+            }
+
+            let name = if is_nvidia {
+                sys::av_opt_set(priv_data, c"rc".as_ptr(), c"vbr".as_ptr(), 0);
+                c"cq"
+            } else {
+                c"crf"
+            };
+            if !set_private_int(ctx, name, level as i64) {
+                return Err(Error::RateControlNotSupported(config.rate_control));
+            }
+        }
+        RateControl::ConstantQp { qp } => {
+            (*ctx).bit_rate = 0;
+            (*ctx).qmin = qp as i32;
+            (*ctx).qmax = qp as i32;
+
+            if is_nvidia {
+                sys::av_opt_set(priv_data, c"rc".as_ptr(), c"constqp".as_ptr(), 0);
+            }
+            // Codecs without a qp option get the fixed quantizer from qmin == qmax.
+            set_private_int(ctx, c"qp", qp as i64);
+        }
+    }
+
+    if let Some(min_qp) = config.min_qp {
+        (*ctx).qmin = min_qp as i32;
     }
+    if let Some(max_qp) = config.max_qp {
+        (*ctx).qmax = max_qp as i32;
+    }
+
+    Ok(())
+}
+
+/// Set a private option of the codec, returning whether it has the option.
+unsafe fn set_private_int(ctx: *mut sys::AVCodecContext, name: &CStr, value: i64) -> bool {
+    let priv_data = (*ctx).priv_data;
+    !priv_data.is_null() && sys::av_opt_set_int(priv_data, name.as_ptr(), value, 0) >= 0
+}
+
+unsafe fn set_max_bitrate(ctx: *mut sys::AVCodecContext, max_bitrate: u32) {
+    (*ctx).rc_max_rate = max_bitrate as i64;
+    // libx264 ignores the max rate without a VBV buffer, use one second worth.
+    (*ctx).rc_buffer_size = max_bitrate as i32;
 }
 
 extern "C" fn free_frame_droppable<T>(opaque: *mut c_void, _data: *mut u8) {
@@ -214,33 +798,55 @@ impl Drop for Encoder {
 
 struct PacketIterator<'a> {
     enc: Option<&'a mut Encoder>,
-    rotation: usize,
 }
 
 impl<'a> Iterator for PacketIterator<'a> {
     type Item = Result<EncodedPacket, Error>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let enc = self.enc.as_ref()?;
+        let enc = self.enc.as_mut()?;
+
+        if let Some(packet) = enc.pending.pop_front() {
+            return Some(Ok(packet));
+        }
 
         unsafe {
-            let pkt = sys::av_packet_alloc();
+            let mut pkt = sys::av_packet_alloc();
 
             let ret = sys::avcodec_receive_packet(enc.ctx, pkt);
             if ret == sys::AVErrorEAgain || ret == sys::AVErrorEof {
+                sys::av_packet_free(&mut pkt);
+
                 // Remove enc to stop producing packets.
                 self.enc = None;
                 return None;
             } else if ret < 0 {
+                sys::av_packet_free(&mut pkt);
                 return Some(Err(Error::ReceivePacketFailed(
                     ret,
                     err_code_to_string(ret),
                 )));
             }
 
+            (*pkt).time_base = (*enc.ctx).time_base;
+
+            if enc.new_extradata {
+                enc.new_extradata = false;
+
+                let extradata = enc.extradata();
+                let kind = sys::AVPacketSideDataType::AV_PKT_DATA_NEW_EXTRADATA;
+                let dst = sys::av_packet_new_side_data(pkt, kind, extradata.len());
+                if dst.is_null() {
+                    sys::av_packet_free(&mut pkt);
+                    return Some(Err(Error::AlllocateFailed("av_packet_new_side_data")));
+                }
+                ptr::copy_nonoverlapping(extradata.as_ptr(), dst, extradata.len());
+            }
+
             Some(Ok(EncodedPacket {
                 pkt,
-                rotation: self.rotation,
+                rotation: enc.last_rotation,
+                resolution_change: enc.resolution_change.take(),
             }))
         }
     }
@@ -248,13 +854,23 @@ impl<'a> Iterator for PacketIterator<'a> {
 
 struct EncodedPacket {
     pkt: *mut sys::AVPacket,
+    /// Fallback for when the packet has no opaque_ref.
     rotation: usize,
+    resolution_change: Option<(usize, usize)>,
 }
 
 // SAFETY: AVPacket is fine to send between threads.
 unsafe impl Send for EncodedPacket {}
 unsafe impl Sync for EncodedPacket {}
 
+impl EncodedPacket {
+    fn opaque(&self) -> Option<&Opaque> {
+        // SAFETY: The pointer is valid while self is alive, and opaque_ref is either null or
+        // copied from a frame by libavcodec, where we put it in Encoder::encode.
+        unsafe { Opaque::from_buffer((*self.pkt).opaque_ref) }
+    }
+}
+
 impl Packet<[u8]> for EncodedPacket {
     type Droppable = Self;
 
@@ -263,7 +879,26 @@ impl Packet<[u8]> for EncodedPacket {
     }
 
     fn rotation(&self) -> usize {
-        self.rotation
+        self.opaque().map(|o| o.rotation).unwrap_or(self.rotation)
+    }
+
+    fn metadata(&self) -> Option<Metadata> {
+        self.opaque().and_then(|o| o.metadata.clone())
+    }
+
+    fn side_data(&self) -> Vec<PacketSideData<'_>> {
+        // SAFETY: The pointer and the side data are valid while self is alive.
+        unsafe {
+            let count = (*self.pkt).side_data_elems as usize;
+            if count == 0 {
+                return vec![];
+            }
+
+            std::slice::from_raw_parts((*self.pkt).side_data, count)
+                .iter()
+                .map(|sd| PacketSideData::from_av(sd))
+                .collect()
+        }
     }
 
     fn keyframe(&self) -> bool {
@@ -274,6 +909,23 @@ impl Packet<[u8]> for EncodedPacket {
         unsafe { (*self.pkt).pts }
     }
 
+    fn dts(&self) -> i64 {
+        unsafe { (*self.pkt).dts }
+    }
+
+    fn duration(&self) -> i64 {
+        unsafe { (*self.pkt).duration }
+    }
+
+    fn time_base(&self) -> Option<Rational> {
+        // SAFETY: The pointer is valid while self is alive.
+        Some(unsafe { (*self.pkt).time_base }.into())
+    }
+
+    fn resolution_change(&self) -> Option<(usize, usize)> {
+        self.resolution_change
+    }
+
     fn into_droppable(self) -> Self::Droppable {
         self
     }
@@ -291,29 +943,452 @@ impl Packet<[u8]> for EncodedPacket {
 impl Drop for EncodedPacket {
     fn drop(&mut self) {
         unsafe {
-            sys::av_packet_unref(self.pkt);
+            sys::av_packet_free(&mut self.pkt);
         }
     }
 }
 
 #[cfg(test)]
 mod test {
+    use std::sync::Arc;
+
     use super::*;
+    use crate::test_util::{
+        encode_frames, encoder, flush, test_config, test_frame, x264, TestPacket,
+    };
+    use crate::{PictureType, RegionOfInterest};
 
     #[test]
     fn test_instantiate_encoder() {
-        let codec = Codec::list(CodecKind::Encoder)
-            .find(|c| c.name() == "libx264")
-            .unwrap();
+        Encoder::new(&x264(), &test_config()).unwrap();
+    }
+
+    #[test]
+    fn test_unknown_option() {
         let config = EncoderConfig {
-            bitrate: 2_000_000,
-            width: 1024,
-            height: 768,
-            fps: 30,
-            thread_count: 4,
-            max_b_frames: 0,
-            keyframe_distance: 300,
+            options: vec![
+                ("preset".to_string(), "veryfast".to_string()),
+                ("no-such-option".to_string(), "1".to_string()),
+            ],
+            ..test_config()
+        };
+        assert!(matches!(
+            Encoder::new(&x264(), &config),
+            Err(Error::UnusedOptions(options)) if options == ["no-such-option"]
+        ));
+    }
+
+    #[test]
+    fn test_rate_control() {
+        let config = EncoderConfig {
+            rate_control: RateControl::Cbr {
+                bitrate: 1_000_000,
+                buffer_size: 500_000,
+            },
+            ..test_config()
+        };
+        let enc = Encoder::new(&x264(), &config).unwrap();
+        unsafe {
+            assert_eq!((*enc.ctx).bit_rate, 1_000_000);
+            assert_eq!((*enc.ctx).rc_min_rate, 1_000_000);
+            assert_eq!((*enc.ctx).rc_max_rate, 1_000_000);
+            assert_eq!((*enc.ctx).rc_buffer_size, 500_000);
+        }
+
+        let quality = EncoderConfig {
+            rate_control: RateControl::ConstantQuality {
+                level: 23,
+                max_bitrate: None,
+            },
+            ..test_config()
+        };
+        let enc = Encoder::new(&x264(), &quality).unwrap();
+        assert_eq!(unsafe { (*enc.ctx).bit_rate }, 0);
+
+        let qp = EncoderConfig {
+            rate_control: RateControl::ConstantQp { qp: 30 },
+            ..test_config()
+        };
+        Encoder::new(&x264(), &qp).unwrap();
+
+        // mpeg4 has no crf, but takes a fixed quantizer through qmin and qmax.
+        let mpeg4 = encoder("mpeg4");
+        assert!(matches!(
+            Encoder::new(&mpeg4, &quality),
+            Err(Error::RateControlNotSupported(
+                RateControl::ConstantQuality { level: 23, .. }
+            ))
+        ));
+        let enc = Encoder::new(&mpeg4, &qp).unwrap();
+        unsafe {
+            assert_eq!((*enc.ctx).qmin, 30);
+            assert_eq!((*enc.ctx).qmax, 30);
+        }
+    }
+
+    #[test]
+    fn test_global_header() {
+        let enc = Encoder::new(&x264(), &test_config()).unwrap();
+        assert!(enc.extradata().is_empty());
+
+        let config = EncoderConfig {
+            global_header: true,
+            ..test_config()
+        };
+        let enc = Encoder::new(&x264(), &config).unwrap();
+        assert!(!enc.extradata().is_empty());
+    }
+
+    #[test]
+    fn test_repeat_headers() {
+        // Whether an Annex B packet has an SPS NAL unit.
+        fn has_sps(data: &[u8]) -> bool {
+            data.windows(4)
+                .any(|w| w[..3] == [0, 0, 1] && w[3] & 0x1f == 7)
+        }
+
+        // The headers are repeated even when the caller passes x264-params of their own.
+        let x264_params = vec![("x264-params".to_string(), "ref=2".to_string())];
+        for (repeat_headers, options) in [
+            (false, vec![]),
+            (true, vec![]),
+            (false, x264_params.clone()),
+            (true, x264_params),
+        ] {
+            let config = EncoderConfig {
+                keyframe_distance: 5,
+                global_header: true,
+                repeat_headers,
+                options,
+                ..test_config()
+            };
+            let mut enc = Encoder::new(&x264(), &config).unwrap();
+
+            let packets = encode_frames(&mut enc, 0..10);
+            let keyframes: Vec<_> = packets.iter().filter(|p| p.keyframe).collect();
+            assert_eq!(keyframes.len(), 2);
+            for packet in &packets {
+                let expected = packet.keyframe && repeat_headers;
+                assert_eq!(has_sps(&packet.data.0), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_new_extradata_after_reopen() {
+        let config = EncoderConfig {
+            global_header: true,
+            ..test_config()
+        };
+        let mut enc = Encoder::new(&x264(), &config).unwrap();
+        let old_extradata = enc.extradata().to_vec();
+
+        let mut new_extradata = vec![];
+        for pts in 0..10 {
+            let (width, height) = if pts < 5 { (64, 48) } else { (32, 24) };
+            for packet in enc.encode(test_frame(width, height, pts), false).unwrap() {
+                let packet = packet.unwrap();
+                for side_data in packet.side_data() {
+                    if let PacketSideData::NewExtradata(data) = side_data {
+                        new_extradata.push((packet.pts(), data.to_vec()));
+                    }
+                }
+            }
+        }
+
+        // Only the first packet of the re-opened encoder carries the new parameter sets.
+        assert_ne!(enc.extradata(), old_extradata);
+        assert_eq!(new_extradata, [(5, enc.extradata().to_vec())]);
+    }
+
+    #[test]
+    fn test_encode_after_partial_flush() {
+        let config = EncoderConfig {
+            max_b_frames: 2,
+            ..test_config()
+        };
+        let mut enc = Encoder::new(&x264(), &config).unwrap();
+
+        let mut count = encode_frames(&mut enc, 0..10).len();
+        // Take a single packet and leave the rest in the flushed encoder.
+        count += enc.flush().unwrap().take(1).count();
+        count += encode_frames(&mut enc, 10..20).len();
+        count += flush(&mut enc).len();
+
+        assert_eq!(count, 20);
+    }
+
+    #[test]
+    fn test_set_bitrate_without_keyframe() {
+        let mut enc = Encoder::new(&x264(), &test_config()).unwrap();
+
+        let mut packets = encode_frames(&mut enc, 0..10);
+        enc.set_bitrate(250_000).unwrap();
+        packets.extend(encode_frames(&mut enc, 10..20));
+        packets.extend(flush(&mut enc));
+
+        // The context is kept, so only the first frame is a keyframe.
+        assert_eq!(packets.len(), 20);
+        assert_eq!(packets.iter().filter(|p| p.keyframe).count(), 1);
+        assert!(packets[0].keyframe);
+    }
+
+    #[test]
+    fn test_set_bitrate_libvpx() {
+        let mut enc = Encoder::new(&encoder("libvpx"), &test_config()).unwrap();
+
+        let mut packets = encode_frames(&mut enc, 0..10);
+        enc.set_bitrate(250_000).unwrap();
+        assert_eq!(unsafe { (*enc.ctx).bit_rate }, 250_000);
+        packets.extend(encode_frames(&mut enc, 10..20));
+        packets.extend(flush(&mut enc));
+
+        // Reconfigured in place, so there is no keyframe from a re-open.
+        assert_eq!(packets.len(), 20);
+        assert_eq!(packets.iter().filter(|p| p.keyframe).count(), 1);
+    }
+
+    #[test]
+    fn test_resize() {
+        let config = EncoderConfig {
+            max_b_frames: 2,
+            ..test_config()
+        };
+        let mut enc = Encoder::new(&x264(), &config).unwrap();
+
+        let mut packets = encode_frames(&mut enc, 0..10);
+        let frame = test_frame(32, 24, 10);
+        packets.extend(enc.encode(frame, false).unwrap().map(TestPacket::new));
+        packets.extend(encode_frames(&mut enc, 11..20));
+        packets.extend(flush(&mut enc));
+        assert_eq!(packets.len(), 20);
+
+        // The packets drained from the old context come first and don't report a change.
+        let (old, new) = packets.split_at(10);
+        assert!(old
+            .iter()
+            .all(|p| p.pts < 10 && p.resolution_change.is_none()));
+
+        assert!(
+            new[0].keyframe,
+            "the first packet after a resize must be a keyframe"
+        );
+        assert_eq!(new[0].resolution_change, Some((32, 24)));
+        assert!(new[1..]
+            .iter()
+            .all(|p| p.pts >= 10 && p.resolution_change.is_none()));
+    }
+
+    #[test]
+    fn test_regions_of_interest() {
+        let roi = FrameSideData::RegionsOfInterest(vec![RegionOfInterest {
+            top: 0,
+            bottom: 24,
+            left: 0,
+            right: 32,
+            qoffset: Rational::new(-1, 2),
+        }]);
+        let frame = |pts| {
+            let mut frame = test_frame(64, 48, pts);
+            frame.add_side_data(&roi).unwrap();
+            frame
+        };
+
+        // The ultrafast preset turns adaptive quantization off, so libx264 would drop the regions.
+        let mut enc = Encoder::new(&x264(), &test_config()).unwrap();
+        assert!(matches!(
+            enc.encode(frame(0), false),
+            Err(Error::RoiNotSupported("libx264"))
+        ));
+
+        // A rejected frame of a new size doesn't re-open the encoder.
+        let mut small = test_frame(32, 24, 0);
+        small.add_side_data(&roi).unwrap();
+        assert!(matches!(
+            enc.encode(small, false),
+            Err(Error::RoiNotSupported("libx264"))
+        ));
+        assert_eq!((enc.width(), enc.height()), (64, 48));
+
+        let option = |k: &str, v: &str| (k.to_string(), v.to_string());
+        let config = EncoderConfig {
+            options: vec![
+                option("preset", "veryfast"),
+                option("x264-params", "ref=2:aq-mode=0"),
+            ],
+            ..test_config()
+        };
+        let mut enc = Encoder::new(&x264(), &config).unwrap();
+        assert!(matches!(
+            enc.encode(frame(0), false),
+            Err(Error::RoiNotSupported("libx264"))
+        ));
+
+        for options in [
+            vec![option("aq-mode", "1")],
+            vec![option("preset", "veryfast")],
+            vec![option("x264-params", "aq-mode=2")],
+            vec![option("x264opts", "aq_mode=1:ref=2")],
+        ] {
+            let config = EncoderConfig {
+                options,
+                ..test_config()
+            };
+            let mut enc = Encoder::new(&x264(), &config).unwrap();
+
+            let mut count = 0;
+            for pts in 0..5 {
+                count += enc.encode(frame(pts), false).unwrap().count();
+            }
+            count += flush(&mut enc).len();
+            assert_eq!(count, 5);
+        }
+    }
+
+    #[test]
+    fn test_quality_stats() {
+        let config = EncoderConfig {
+            psnr: true,
+            ..test_config()
+        };
+        let mut enc = Encoder::new(&x264(), &config).unwrap();
+
+        let mut stats = vec![];
+        for pts in 0..5 {
+            let frame = test_frame(64, 48, pts);
+            for packet in enc.encode(frame, false).unwrap() {
+                stats.push(packet.unwrap().stats().unwrap());
+            }
+        }
+        for packet in enc.flush().unwrap() {
+            stats.push(packet.unwrap().stats().unwrap());
+        }
+
+        assert_eq!(stats.len(), 5);
+        assert_eq!(stats[0].picture_type, PictureType::I);
+        assert!(stats[1..].iter().all(|s| s.picture_type == PictureType::P));
+        // One error value per plane.
+        assert!(stats.iter().all(|s| s.errors.len() == 3));
+    }
+
+    #[test]
+    fn test_packet_duration() {
+        let config = EncoderConfig {
+            time_base: Rational::new(1, 90_000),
+            max_b_frames: 2,
+            ..test_config()
+        };
+        let mut enc = Encoder::new(&x264(), &config).unwrap();
+
+        let mut packets = encode_frames(&mut enc, (0..10).map(|i| i * 3000));
+        packets.extend(flush(&mut enc));
+
+        assert_eq!(packets.len(), 10);
+        assert!(packets.iter().all(|p| p.duration == 3000));
+    }
+
+    #[test]
+    fn test_metadata_follows_frames() {
+        let config = EncoderConfig {
+            max_b_frames: 2,
+            ..test_config()
+        };
+        let mut enc = Encoder::new(&x264(), &config).unwrap();
+
+        let mut packets = vec![];
+        for pts in 0..10_i64 {
+            let mut frame = test_frame(64, 48, pts);
+            frame.set_metadata(Some(Arc::new(pts)));
+
+            packets.extend(enc.encode(frame, false).unwrap().map(TestPacket::new));
+        }
+        packets.extend(flush(&mut enc));
+
+        assert_eq!(packets.len(), 10);
+        for packet in packets {
+            let id = packet.metadata.unwrap().downcast::<i64>().unwrap();
+            assert_eq!(packet.pts, *id);
+        }
+    }
+
+    #[test]
+    fn test_encode_borrowed() {
+        let mut enc = Encoder::new(&x264(), &test_config()).unwrap();
+
+        let mut frame = test_frame(64, 48, 0);
+        let mut count = 0;
+        for pts in 0..5 {
+            frame.set_pts(pts);
+            count += enc.encode_borrowed(&frame, false).unwrap().count();
+        }
+        count += flush(&mut enc).len();
+
+        assert_eq!(count, 5);
+    }
+
+    #[test]
+    fn test_validate_frame() {
+        struct BrokenFrame {
+            data: Vec<u8>,
+            stride: usize,
+        }
+
+        impl Frame for BrokenFrame {
+            type Droppable = Vec<u8>;
+
+            fn width(&self) -> usize {
+                64
+            }
+
+            fn height(&self) -> usize {
+                48
+            }
+
+            fn plane_count(&self) -> usize {
+                3
+            }
+
+            fn get_plane(&self, _i: usize) -> &[u8] {
+                &self.data
+            }
+
+            fn get_stride(&self, i: usize) -> usize {
+                if i == 0 {
+                    self.stride
+                } else {
+                    self.stride / 2
+                }
+            }
+
+            fn pts(&self) -> i64 {
+                0
+            }
+
+            fn rotation(&self) -> usize {
+                0
+            }
+
+            fn into_droppable(self) -> Self::Droppable {
+                self.data
+            }
+        }
+
+        let short = BrokenFrame {
+            data: vec![0; 64 * 48 - 1],
+            stride: 64,
+        };
+        assert!(matches!(
+            validate_frame(&short, PixelFormat::Yuv420p),
+            Err(Error::PlaneTooSmall(0, _, _))
+        ));
+
+        let narrow = BrokenFrame {
+            data: vec![0; 64 * 48],
+            stride: 32,
         };
-        Encoder::new(&codec, &config).unwrap();
+        assert!(matches!(
+            validate_frame(&narrow, PixelFormat::Yuv420p),
+            Err(Error::InvalidStride(0, 32, 64))
+        ));
     }
 }
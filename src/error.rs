@@ -1,5 +1,7 @@
 use thiserror::Error;
 
+use crate::{PixelFormat, RateControl};
+
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("Codec not found: {0}")]
@@ -32,6 +34,54 @@ pub enum Error {
     #[error("Failed to receive decoded frame: {0} {1}")]
     ReceiveFrameFailed(i32, String),
 
+    #[error("Invalid option: {0}")]
+    InvalidOption(String),
+
+    #[error("Options not recognised by the codec: {}", .0.join(", "))]
+    UnusedOptions(Vec<String>),
+
+    #[error("Unsupported pixel format: {0}")]
+    UnsupportedPixelFormat(i32),
+
+    #[error("Buffer too small: {0} bytes, need {1}")]
+    BufferTooSmall(usize, usize),
+
+    #[error("Failed to create scaler from {0:?} to {1:?}")]
+    CreateScalerFailed(PixelFormat, PixelFormat),
+
+    #[error("Failed to scale frame: {0}")]
+    ScaleFailed(i32),
+
+    #[error("Can't set bitrate with {0:?} rate control")]
+    BitrateNotSupported(RateControl),
+
+    #[error("Codec does not support {0:?} rate control")]
+    RateControlNotSupported(RateControl),
+
+    #[error("Unsupported side data type: {0}")]
+    UnsupportedSideData(u32),
+
+    #[error("Codec does not support regions of interest: {0}")]
+    RoiNotSupported(&'static str),
+
+    #[error("Decoder limit exceeded: {0} is {1}, limit is {2}")]
+    LimitExceeded(&'static str, u64, u64),
+
+    #[error("Invalid frame dimensions: {0}x{1}")]
+    InvalidDimensions(usize, usize),
+
+    #[error("Frame pixel format {0:?} does not match the encoder's {1:?}")]
+    PixelFormatMismatch(PixelFormat, PixelFormat),
+
+    #[error("Frame has {0} planes, the pixel format has {1}")]
+    InvalidPlaneCount(usize, usize),
+
+    #[error("Stride of plane {0} is {1}, need at least {2}")]
+    InvalidStride(usize, usize, usize),
+
+    #[error("Plane {0} is {1} bytes, need {2}")]
+    PlaneTooSmall(usize, usize, usize),
+
     #[error("Failed to allocate memory: {0}")]
     AlllocateFailed(&'static str),
 }
@@ -0,0 +1,104 @@
+use std::ptr;
+
+use crate::MAX_PLANES;
+
+use super::{sys, Error, PixelFormat, VideoFrame};
+
+/// Alignment of the rows, enough for the SIMD code in libavcodec.
+const STRIDE_ALIGN: usize = 64;
+
+/// A pool of frames with the same pixel format and size.
+///
+/// The planes come from one `AVBufferPool` each, so once the encoder releases a frame its memory
+/// is handed out again by [`FramePool::get`] rather than freed.
+pub(crate) struct FramePool {
+    pools: [*mut sys::AVBufferPool; MAX_PLANES],
+    strides: [usize; MAX_PLANES],
+    pixel_format: PixelFormat,
+    width: usize,
+    height: usize,
+}
+
+impl FramePool {
+    pub(crate) fn new(
+        pixel_format: PixelFormat,
+        width: usize,
+        height: usize,
+    ) -> Result<Self, Error> {
+        let mut pool = FramePool {
+            pools: [ptr::null_mut(); MAX_PLANES],
+            strides: [0; MAX_PLANES],
+            pixel_format,
+            width,
+            height,
+        };
+
+        for i in 0..pixel_format.plane_count() {
+            let stride = pixel_format
+                .line_size(i, width)
+                .next_multiple_of(STRIDE_ALIGN);
+            // Encoders may read a little past the last row with SIMD.
+            let size = stride * pixel_format.plane_height(i, height)
+                + sys::AV_INPUT_BUFFER_PADDING_SIZE as usize;
+
+            // NB: The type expected here differs based on the underlying version of libavutil,
+            // see Decoder::decode.
+            #[allow(clippy::useless_conversion)]
+            let buffer_pool = unsafe { sys::av_buffer_pool_init(size.try_into().unwrap(), None) };
+            if buffer_pool.is_null() {
+                return Err(Error::AlllocateFailed(
+                    "av_buffer_pool_init for FramePool::new",
+                ));
+            }
+
+            pool.pools[i] = buffer_pool;
+            pool.strides[i] = stride;
+        }
+
+        Ok(pool)
+    }
+
+    /// Whether the pool hands out frames of this format and size.
+    pub(crate) fn matches(&self, pixel_format: PixelFormat, width: usize, height: usize) -> bool {
+        self.pixel_format == pixel_format && self.width == width && self.height == height
+    }
+
+    /// Get a frame from the pool, with uninitialised planes.
+    pub(crate) fn get(&self) -> Result<VideoFrame, Error> {
+        let mut frame = unsafe { sys::av_frame_alloc() };
+        if frame.is_null() {
+            return Err(Error::AlllocateFailed("av_frame_alloc for FramePool::get"));
+        }
+
+        unsafe {
+            (*frame).format = self.pixel_format.as_av() as i32;
+            (*frame).width = self.width as i32;
+            (*frame).height = self.height as i32;
+
+            for i in 0..self.pixel_format.plane_count() {
+                let buf = sys::av_buffer_pool_get(self.pools[i]);
+                if buf.is_null() {
+                    sys::av_frame_free(&mut frame);
+                    return Err(Error::AlllocateFailed(
+                        "av_buffer_pool_get for FramePool::get",
+                    ));
+                }
+
+                (*frame).buf[i] = buf;
+                (*frame).data[i] = (*buf).data;
+                (*frame).linesize[i] = self.strides[i] as i32;
+            }
+
+            Ok(VideoFrame::from_raw(frame))
+        }
+    }
+}
+
+impl Drop for FramePool {
+    fn drop(&mut self) {
+        for pool in &mut self.pools {
+            // The buffers still in use keep the pool alive until they are released.
+            unsafe { sys::av_buffer_pool_uninit(pool) };
+        }
+    }
+}
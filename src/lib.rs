@@ -1,28 +1,74 @@
 #![allow(clippy::needless_lifetimes)]
 
+use std::any::Any;
 use std::ffi::c_char;
 use std::ffi::c_int;
 use std::ffi::c_void;
 use std::ffi::CStr;
 use std::ptr;
+use std::sync::Arc;
 
 mod sys;
-use sys::AVPixelFormat as PixelFormat;
+
+mod pixel_format;
+pub use pixel_format::PixelFormat;
+
+mod rational;
+pub use rational::Rational;
+
+mod video_frame;
+pub use video_frame::VideoFrame;
+
+mod scaler;
+pub use scaler::{ScaleAlgorithm, Scaler, ScalerConfig};
 
 mod encoder;
-pub use encoder::{Encoder, EncoderConfig};
+pub use encoder::{Encoder, EncoderConfig, RateControl};
 
 mod decoder;
-pub use decoder::{DecodeThreadType, Decoder, DecoderConfig};
+pub use decoder::{
+    DecodeErrors, DecodeThreadType, Decoder, DecoderConfig, DecoderLimits, ErrorConcealment,
+    ErrorRecognition,
+};
 
 mod error;
 pub use error::Error;
 
+mod allocator;
+pub use allocator::{FrameAllocator, FrameBuffer, PoolAllocator, SharedAllocator};
+
+mod dict;
+
+mod side_data;
+pub use side_data::{
+    ContentLightLevel, FrameSideData, MasteringDisplayMetadata, MotionVector, PacketSideData,
+    PictureType, QualityStats, RegionOfInterest,
+};
+
+mod opaque;
+
+mod frame_pool;
+
+/// Fixtures shared by the encoder and decoder tests.
+#[cfg(test)]
+mod test_util;
+
+mod option;
+pub use option::{CodecOption, OptionConstant, OptionKind, OptionValue};
+
 use tracing::Level;
 use tracing::{debug, error, info, trace, warn};
 
 const MAX_PLANES: usize = sys::AV_NUM_DATA_POINTERS as usize;
 
+/// Arbitrary user data attached to a [`Frame`] or [`Packet`], e.g. a capture time or RTP
+/// timestamp.
+///
+/// Metadata on a frame passed to the encoder comes out on the packet encoded from it, and metadata
+/// on a packet passed to the decoder comes out on the frames decoded from it. Use
+/// `Arc::downcast` to get the concrete type back.
+pub type Metadata = Arc<dyn Any + Send + Sync>;
+
 pub trait Frame {
     type Droppable: Drop + Send + Sync;
 
@@ -33,8 +79,45 @@ pub trait Frame {
     fn get_stride(&self, i: usize) -> usize;
     fn pts(&self) -> i64;
 
+    /// Layout of the planes returned by `get_plane`.
+    fn pixel_format(&self) -> PixelFormat {
+        PixelFormat::Yuv420p
+    }
+
     fn rotation(&self) -> usize;
 
+    /// Time base of `pts`.
+    ///
+    /// `None` means the pts is already in the time base the encoder is configured with, otherwise
+    /// the encoder rescales it.
+    fn time_base(&self) -> Option<Rational> {
+        None
+    }
+
+    /// Side data attached to the frame.
+    ///
+    /// When encoding, the side data is passed on to the encoder, which emits what it supports
+    /// in-band. libx264 writes A53 captions by default (`a53cc`) and SEI user data when the
+    /// `udu_sei` option is set.
+    fn side_data(&self) -> Vec<FrameSideData<'_>> {
+        vec![]
+    }
+
+    /// Whether the decoder flagged the frame as corrupt, e.g. because a reference was lost.
+    fn is_corrupt(&self) -> bool {
+        false
+    }
+
+    /// The errors the decoder ran into while decoding the frame.
+    fn decode_errors(&self) -> DecodeErrors {
+        DecodeErrors::default()
+    }
+
+    /// User data to carry through the encoder.
+    fn metadata(&self) -> Option<Metadata> {
+        None
+    }
+
     fn into_droppable(self) -> Self::Droppable;
 
     // Shortcut when using libavcodec decoder -> libavcodec encoder
@@ -47,6 +130,50 @@ pub trait Frame {
     }
 }
 
+/// Check that the planes of `frame` hold a complete image of its size and pixel format, so that
+/// libavcodec and libswscale don't read out of bounds.
+pub(crate) fn validate_frame<T: Frame>(frame: &T, pixel_format: PixelFormat) -> Result<(), Error> {
+    let (width, height) = (frame.width(), frame.height());
+    let size_ok = width > 0
+        && height > 0
+        && u32::try_from(width).is_ok()
+        && u32::try_from(height).is_ok()
+        && unsafe {
+            sys::av_image_check_size(width as u32, height as u32, 0, ptr::null_mut()) >= 0
+        };
+    if !size_ok {
+        return Err(Error::InvalidDimensions(width, height));
+    }
+
+    let format = frame.pixel_format();
+    if format != pixel_format {
+        return Err(Error::PixelFormatMismatch(format, pixel_format));
+    }
+
+    let plane_count = format.plane_count();
+    if frame.plane_count() != plane_count {
+        return Err(Error::InvalidPlaneCount(frame.plane_count(), plane_count));
+    }
+
+    for i in 0..plane_count {
+        let stride = frame.get_stride(i);
+        let line_size = format.line_size(i, width);
+        if stride < line_size {
+            return Err(Error::InvalidStride(i, stride, line_size));
+        }
+
+        let len = frame.get_plane(i).len();
+        let need = stride
+            .checked_mul(format.plane_height(i, height))
+            .ok_or(Error::InvalidStride(i, stride, line_size))?;
+        if len < need {
+            return Err(Error::PlaneTooSmall(i, len, need));
+        }
+    }
+
+    Ok(())
+}
+
 pub trait Packet<Data>
 where
     Data: ?Sized,
@@ -58,6 +185,52 @@ where
     fn keyframe(&self) -> bool;
     fn pts(&self) -> i64;
 
+    /// Decoding timestamp, `AV_NOPTS_VALUE` if unknown. Only differs from the pts when the stream
+    /// has B-frames.
+    fn dts(&self) -> i64 {
+        sys::AVNoPtsValue
+    }
+
+    /// Duration of the packet in its time base, 0 if unknown.
+    fn duration(&self) -> i64 {
+        0
+    }
+
+    /// The new width and height, if the encoder was re-initialised for a new input size just
+    /// before producing this packet.
+    fn resolution_change(&self) -> Option<(usize, usize)> {
+        None
+    }
+
+    /// Time base of `pts`, `dts` and `duration`.
+    ///
+    /// `None` means the timestamps are already in the time base the decoder is configured with,
+    /// otherwise the decoder rescales them.
+    fn time_base(&self) -> Option<Rational> {
+        None
+    }
+
+    /// Side data attached to the packet.
+    ///
+    /// When decoding, [`PacketSideData::Other`] is skipped and the rest is passed on to the
+    /// decoder.
+    fn side_data(&self) -> Vec<PacketSideData<'_>> {
+        vec![]
+    }
+
+    /// Encoder statistics, if the encoder exports them.
+    fn stats(&self) -> Option<QualityStats> {
+        self.side_data().into_iter().find_map(|sd| match sd {
+            PacketSideData::QualityStats(data) => QualityStats::parse(data),
+            _ => None,
+        })
+    }
+
+    /// User data to carry through the decoder.
+    fn metadata(&self) -> Option<Metadata> {
+        None
+    }
+
     fn into_droppable(self) -> Self::Droppable;
 
     // Shortcut when using libavcodec encoder -> libavcodec decoder
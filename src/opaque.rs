@@ -0,0 +1,51 @@
+use std::ffi::c_void;
+
+use super::{sys, Metadata};
+
+/// Per-frame data we carry through libavcodec in `opaque_ref`.
+///
+/// With `AV_CODEC_FLAG_COPY_OPAQUE` set, libavcodec moves `opaque_ref` from input frames to the
+/// matching output packets (encoding) and from input packets to output frames (decoding), so this
+/// ends up attached to the right output regardless of reordering or threading delay.
+pub(crate) struct Opaque {
+    pub rotation: usize,
+    pub metadata: Option<Metadata>,
+}
+
+impl Opaque {
+    /// Move into a new reference counted buffer, suitable for `opaque_ref`.
+    pub(crate) fn into_buffer(self) -> *mut sys::AVBufferRef {
+        let opaque = Box::into_raw(Box::new(self));
+
+        unsafe {
+            sys::av_buffer_create(
+                opaque.cast(),
+                // NB: The type expected here differs based on the underlying version of
+                // libavcodec, see Decoder::decode.
+                #[allow(clippy::useless_conversion)]
+                std::mem::size_of::<Opaque>().try_into().unwrap(),
+                Some(free_opaque),
+                opaque.cast(),
+                sys::AV_BUFFER_FLAG_READONLY as i32,
+            )
+        }
+    }
+
+    /// Read the value in an `opaque_ref` buffer created by [`Opaque::into_buffer`].
+    ///
+    /// **SAFETY:** `buf` must be null or a buffer created by `into_buffer`, that stays alive for
+    /// `'a`.
+    pub(crate) unsafe fn from_buffer<'a>(buf: *const sys::AVBufferRef) -> Option<&'a Opaque> {
+        if buf.is_null() {
+            return None;
+        }
+        let data: *const Opaque = (*buf).data.cast();
+        data.as_ref()
+    }
+}
+
+extern "C" fn free_opaque(opaque: *mut c_void, _data: *mut u8) {
+    unsafe {
+        let _ = Box::<Opaque>::from_raw(opaque.cast());
+    };
+}
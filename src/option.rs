@@ -0,0 +1,221 @@
+use std::ffi::c_void;
+use std::ffi::CStr;
+use std::ptr;
+
+use super::{sys, Codec};
+
+/// Description of an AVOption exposed by a codec.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CodecOption {
+    /// Name used to set the option, e.g. in `EncoderConfig::options`.
+    pub name: &'static str,
+    /// Short description of the option.
+    pub help: Option<&'static str>,
+    /// The type of value the option takes.
+    pub kind: OptionKind,
+    /// Value used if the option is not set.
+    pub default: OptionValue,
+    /// Minimum valid value for numeric options.
+    pub min: f64,
+    /// Maximum valid value for numeric options.
+    pub max: f64,
+    /// Named values the option accepts in place of a number, e.g. presets.
+    pub constants: Vec<OptionConstant>,
+}
+
+/// A named value accepted by a [`CodecOption`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OptionConstant {
+    pub name: &'static str,
+    pub help: Option<&'static str>,
+    pub value: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OptionKind {
+    Flags,
+    Int,
+    Int64,
+    UInt64,
+    Double,
+    Float,
+    String,
+    Rational,
+    Binary,
+    Dict,
+    ImageSize,
+    PixelFormat,
+    SampleFormat,
+    VideoRate,
+    Duration,
+    Color,
+    Bool,
+    ChannelLayout,
+    /// A type not known to this crate.
+    Other,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OptionValue {
+    /// The option has no default.
+    None,
+    Int(i64),
+    Double(f64),
+    String(&'static str),
+}
+
+impl Codec {
+    /// List the private options of this codec, i.e. the codec specific knobs such as `preset` for
+    /// libx264 or `cpu-used` for libvpx.
+    pub fn options(&self) -> Vec<CodecOption> {
+        // SAFETY: The codec and its AVClass are valid until process exit.
+        unsafe {
+            if (*self.ptr).priv_class.is_null() {
+                return vec![];
+            }
+
+            // av_opt_next reads the AVClass from the first field of the object it's given, so
+            // pointing it at priv_class lets us walk the options without allocating a context.
+            let obj: *const c_void = ptr::addr_of!((*self.ptr).priv_class).cast();
+
+            let mut all = vec![];
+            let mut opt: *const sys::AVOption = ptr::null();
+            loop {
+                opt = sys::av_opt_next(obj, opt);
+                if opt.is_null() {
+                    break;
+                }
+                all.push(&*opt);
+            }
+
+            let mut options: Vec<CodecOption> = all
+                .iter()
+                .filter(|o| o.type_ != sys::AVOptionType::AV_OPT_TYPE_CONST)
+                .map(|o| CodecOption {
+                    name: str_or_empty(o.name),
+                    help: opt_str(o.help),
+                    kind: OptionKind::from(o.type_),
+                    default: default_value(o),
+                    min: o.min,
+                    max: o.max,
+                    constants: vec![],
+                })
+                .collect();
+
+            // Constants are listed as separate options, tied to their option by `unit`.
+            for (opt, option) in all
+                .iter()
+                .filter(|o| o.type_ != sys::AVOptionType::AV_OPT_TYPE_CONST)
+                .zip(options.iter_mut())
+            {
+                let Some(unit) = opt_str(opt.unit) else {
+                    continue;
+                };
+
+                option.constants = all
+                    .iter()
+                    .filter(|o| o.type_ == sys::AVOptionType::AV_OPT_TYPE_CONST)
+                    .filter(|o| opt_str(o.unit) == Some(unit))
+                    .map(|o| OptionConstant {
+                        name: str_or_empty(o.name),
+                        help: opt_str(o.help),
+                        value: o.default_val.i64_,
+                    })
+                    .collect();
+            }
+
+            options
+        }
+    }
+}
+
+unsafe fn opt_str(ptr: *const std::ffi::c_char) -> Option<&'static str> {
+    if ptr.is_null() {
+        return None;
+    }
+    CStr::from_ptr(ptr).to_str().ok()
+}
+
+unsafe fn str_or_empty(ptr: *const std::ffi::c_char) -> &'static str {
+    opt_str(ptr).unwrap_or_default()
+}
+
+unsafe fn default_value(opt: &sys::AVOption) -> OptionValue {
+    use sys::AVOptionType::*;
+
+    match opt.type_ {
+        AV_OPT_TYPE_FLAGS
+        | AV_OPT_TYPE_INT
+        | AV_OPT_TYPE_INT64
+        | AV_OPT_TYPE_UINT64
+        | AV_OPT_TYPE_BOOL
+        | AV_OPT_TYPE_DURATION
+        | AV_OPT_TYPE_PIXEL_FMT
+        | AV_OPT_TYPE_SAMPLE_FMT
+        | AV_OPT_TYPE_CONST => OptionValue::Int(opt.default_val.i64_),
+        // libavutil stores rational defaults as a double.
+        AV_OPT_TYPE_DOUBLE | AV_OPT_TYPE_FLOAT | AV_OPT_TYPE_RATIONAL => {
+            OptionValue::Double(opt.default_val.dbl)
+        }
+        AV_OPT_TYPE_STRING
+        | AV_OPT_TYPE_BINARY
+        | AV_OPT_TYPE_DICT
+        | AV_OPT_TYPE_IMAGE_SIZE
+        | AV_OPT_TYPE_VIDEO_RATE
+        | AV_OPT_TYPE_COLOR
+        | AV_OPT_TYPE_CHLAYOUT => match opt_str(opt.default_val.str_) {
+            Some(s) => OptionValue::String(s),
+            None => OptionValue::None,
+        },
+        #[allow(unreachable_patterns)]
+        _ => OptionValue::None,
+    }
+}
+
+impl From<sys::AVOptionType> for OptionKind {
+    fn from(value: sys::AVOptionType) -> Self {
+        use sys::AVOptionType::*;
+
+        match value {
+            AV_OPT_TYPE_FLAGS => OptionKind::Flags,
+            AV_OPT_TYPE_INT => OptionKind::Int,
+            AV_OPT_TYPE_INT64 => OptionKind::Int64,
+            AV_OPT_TYPE_UINT64 => OptionKind::UInt64,
+            AV_OPT_TYPE_DOUBLE => OptionKind::Double,
+            AV_OPT_TYPE_FLOAT => OptionKind::Float,
+            AV_OPT_TYPE_STRING => OptionKind::String,
+            AV_OPT_TYPE_RATIONAL => OptionKind::Rational,
+            AV_OPT_TYPE_BINARY => OptionKind::Binary,
+            AV_OPT_TYPE_DICT => OptionKind::Dict,
+            AV_OPT_TYPE_IMAGE_SIZE => OptionKind::ImageSize,
+            AV_OPT_TYPE_PIXEL_FMT => OptionKind::PixelFormat,
+            AV_OPT_TYPE_SAMPLE_FMT => OptionKind::SampleFormat,
+            AV_OPT_TYPE_VIDEO_RATE => OptionKind::VideoRate,
+            AV_OPT_TYPE_DURATION => OptionKind::Duration,
+            AV_OPT_TYPE_COLOR => OptionKind::Color,
+            AV_OPT_TYPE_BOOL => OptionKind::Bool,
+            AV_OPT_TYPE_CHLAYOUT => OptionKind::ChannelLayout,
+            #[allow(unreachable_patterns)]
+            _ => OptionKind::Other,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::CodecKind;
+
+    #[test]
+    fn test_list_options() {
+        let codec = Codec::list(CodecKind::Encoder)
+            .find(|c| c.name() == "libx264")
+            .unwrap();
+        let options = codec.options();
+
+        let preset = options.iter().find(|o| o.name == "preset").unwrap();
+        assert_eq!(preset.kind, OptionKind::String);
+
+        println!("{:#?}", options);
+    }
+}
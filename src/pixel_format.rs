@@ -0,0 +1,134 @@
+use std::ffi::CStr;
+
+use super::sys;
+
+macro_rules! pixel_formats {
+    ($($(#[$meta:meta])* $name:ident => $av:ident,)*) => {
+        /// Layout of the pixel data in a [`Frame`](crate::Frame).
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        pub enum PixelFormat {
+            $($(#[$meta])* $name,)*
+        }
+
+        impl PixelFormat {
+            pub(crate) fn as_av(&self) -> sys::AVPixelFormat {
+                match self {
+                    $(PixelFormat::$name => sys::AVPixelFormat::$av,)*
+                }
+            }
+
+            /// Convert from the `format` field of an `AVFrame`.
+            ///
+            /// Returns `None` for formats this crate does not handle.
+            pub(crate) fn from_av(format: i32) -> Option<Self> {
+                $(
+                    if format == sys::AVPixelFormat::$av as i32 {
+                        return Some(PixelFormat::$name);
+                    }
+                )*
+                None
+            }
+        }
+    };
+}
+
+pixel_formats! {
+    /// Planar YUV 4:2:0, 8 bit. Also known as I420.
+    Yuv420p => AV_PIX_FMT_YUV420P,
+    /// Planar YUV 4:2:2, 8 bit.
+    Yuv422p => AV_PIX_FMT_YUV422P,
+    /// Planar YUV 4:4:4, 8 bit.
+    Yuv444p => AV_PIX_FMT_YUV444P,
+    /// Planar YUV 4:2:0, 8 bit, full range.
+    Yuvj420p => AV_PIX_FMT_YUVJ420P,
+    /// Planar YUV 4:2:2, 8 bit, full range.
+    Yuvj422p => AV_PIX_FMT_YUVJ422P,
+    /// Planar YUV 4:4:4, 8 bit, full range.
+    Yuvj444p => AV_PIX_FMT_YUVJ444P,
+    /// Planar YUV 4:2:0 with an alpha plane, 8 bit.
+    Yuva420p => AV_PIX_FMT_YUVA420P,
+    /// Planar YUV 4:2:0, 10 bit little endian.
+    Yuv420p10le => AV_PIX_FMT_YUV420P10LE,
+    /// Planar YUV 4:2:2, 10 bit little endian.
+    Yuv422p10le => AV_PIX_FMT_YUV422P10LE,
+    /// Planar YUV 4:4:4, 10 bit little endian.
+    Yuv444p10le => AV_PIX_FMT_YUV444P10LE,
+    /// Y plane followed by an interleaved UV plane, 4:2:0, 8 bit.
+    Nv12 => AV_PIX_FMT_NV12,
+    /// Y plane followed by an interleaved VU plane, 4:2:0, 8 bit.
+    Nv21 => AV_PIX_FMT_NV21,
+    /// Y plane followed by an interleaved UV plane, 4:2:0, 10 bit little endian in the high bits.
+    P010le => AV_PIX_FMT_P010LE,
+    /// Single luma plane, 8 bit.
+    Gray8 => AV_PIX_FMT_GRAY8,
+    /// Packed RGB 8:8:8.
+    Rgb24 => AV_PIX_FMT_RGB24,
+    /// Packed BGR 8:8:8.
+    Bgr24 => AV_PIX_FMT_BGR24,
+    /// Packed RGBA 8:8:8:8.
+    Rgba => AV_PIX_FMT_RGBA,
+    /// Packed BGRA 8:8:8:8.
+    Bgra => AV_PIX_FMT_BGRA,
+    /// Packed ARGB 8:8:8:8.
+    Argb => AV_PIX_FMT_ARGB,
+    /// Packed ABGR 8:8:8:8.
+    Abgr => AV_PIX_FMT_ABGR,
+}
+
+impl PixelFormat {
+    fn descriptor(&self) -> &'static sys::AVPixFmtDescriptor {
+        // SAFETY: Descriptors are static data in libavutil, and exist for every format we map.
+        unsafe {
+            let desc = sys::av_pix_fmt_desc_get(self.as_av());
+            assert!(!desc.is_null(), "Pixel format descriptor for {:?}", self);
+            &*desc
+        }
+    }
+
+    /// The libavutil name of the format, e.g. `yuv420p`.
+    pub fn name(&self) -> &'static str {
+        let name = unsafe { CStr::from_ptr(self.descriptor().name) };
+        name.to_str().expect("a utf-8 string")
+    }
+
+    /// Number of planes the pixel data is split into.
+    pub fn plane_count(&self) -> usize {
+        let count = unsafe { sys::av_pix_fmt_count_planes(self.as_av()) };
+        count.try_into().expect("Non negative plane count")
+    }
+
+    /// Number of bytes in a row of plane `i` for a frame of the given width, excluding any
+    /// padding.
+    pub fn line_size(&self, i: usize, width: usize) -> usize {
+        let size = unsafe { sys::av_image_get_linesize(self.as_av(), width as i32, i as i32) };
+        size.try_into().expect("Non negative line size")
+    }
+
+    /// Height in rows of plane `i` for a frame of the given height.
+    pub fn plane_height(&self, i: usize, height: usize) -> usize {
+        // Plane 1 and 2 are the chroma planes, which might be subsampled. Luma and alpha are
+        // always full height.
+        if i == 1 || i == 2 {
+            let shift = self.descriptor().log2_chroma_h;
+            (height + (1 << shift) - 1) >> shift
+        } else {
+            height
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_plane_layout() {
+        assert_eq!(PixelFormat::Yuv420p.plane_count(), 3);
+        assert_eq!(PixelFormat::Yuv420p.plane_height(1, 101), 51);
+        assert_eq!(PixelFormat::Yuv422p.plane_height(1, 101), 101);
+        assert_eq!(PixelFormat::Nv12.plane_count(), 2);
+        assert_eq!(PixelFormat::Nv12.plane_height(1, 100), 50);
+        assert_eq!(PixelFormat::Bgra.plane_count(), 1);
+        assert_eq!(PixelFormat::Yuv420p10le.name(), "yuv420p10le");
+    }
+}
@@ -0,0 +1,60 @@
+use super::sys;
+
+/// A rational number, used for time bases and frame rates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Rational {
+    pub num: i32,
+    pub den: i32,
+}
+
+impl Rational {
+    pub const fn new(num: i32, den: i32) -> Self {
+        Rational { num, den }
+    }
+
+    /// Convert a timestamp in this time base to the time base `to`, rounding to the nearest value.
+    ///
+    /// `AV_NOPTS_VALUE`, marking a missing timestamp, is returned unchanged.
+    pub fn rescale(&self, ts: i64, to: Rational) -> i64 {
+        if ts == sys::AVNoPtsValue {
+            return ts;
+        }
+
+        unsafe { sys::av_rescale_q(ts, (*self).into(), to.into()) }
+    }
+
+    /// The value as a floating point number.
+    pub fn as_f64(&self) -> f64 {
+        self.num as f64 / self.den as f64
+    }
+}
+
+impl From<sys::AVRational> for Rational {
+    fn from(value: sys::AVRational) -> Self {
+        Rational::new(value.num, value.den)
+    }
+}
+
+impl From<Rational> for sys::AVRational {
+    fn from(value: Rational) -> Self {
+        sys::AVRational {
+            num: value.num,
+            den: value.den,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rescale() {
+        let rtp = Rational::new(1, 90_000);
+        let ntsc = Rational::new(1001, 30_000);
+
+        assert_eq!(rtp.rescale(3003, ntsc), 1);
+        assert_eq!(ntsc.rescale(30, rtp), 90_090);
+        assert_eq!(rtp.rescale(sys::AVNoPtsValue, ntsc), sys::AVNoPtsValue);
+    }
+}
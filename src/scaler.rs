@@ -0,0 +1,236 @@
+use std::ptr;
+
+use super::{sys, validate_frame, Error, Frame, PixelFormat, VideoFrame};
+
+/// Interpolation used when resizing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ScaleAlgorithm {
+    /// Cheapest, reasonable quality when downscaling.
+    FastBilinear,
+    #[default]
+    Bilinear,
+    Bicubic,
+    /// Nearest neighbour.
+    Point,
+    /// Averages the source pixels, good for large downscaling factors.
+    Area,
+    Lanczos,
+    Spline,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScalerConfig {
+    /// Width of the output frames.
+    pub width: usize,
+    /// Height of the output frames.
+    pub height: usize,
+    /// Pixel format of the output frames.
+    pub pixel_format: PixelFormat,
+    pub algorithm: ScaleAlgorithm,
+}
+
+/// Resizes frames and converts between pixel formats using libswscale.
+///
+/// The underlying `SwsContext` is created on the first call to [`Scaler::scale`] and reused for
+/// as long as the input frames keep the same size and format.
+pub struct Scaler {
+    ctx: *mut sys::SwsContext,
+    config: ScalerConfig,
+}
+
+// SAFETY: The SwsContext is only used through &mut self.
+unsafe impl Send for Scaler {}
+unsafe impl Sync for Scaler {}
+
+impl Scaler {
+    pub fn new(config: &ScalerConfig) -> Self {
+        Scaler {
+            ctx: ptr::null_mut(),
+            config: config.clone(),
+        }
+    }
+
+    pub fn config(&self) -> &ScalerConfig {
+        &self.config
+    }
+
+    /// Scale `frame` to the configured size and format.
+    ///
+    /// Like [`Encoder::encode`](crate::Encoder::encode) this fails if the planes of `frame` don't
+    /// hold a complete image as described by its size and strides.
+    ///
+    /// The pts, time base, rotation and metadata of the input are carried over to the output, which
+    /// can be passed straight to [`Encoder::encode`](crate::Encoder::encode).
+    pub fn scale<T: Frame>(&mut self, frame: &T) -> Result<VideoFrame, Error> {
+        let src_format = frame.pixel_format();
+        validate_frame(frame, src_format)?;
+
+        let (src_width, src_height) = (frame.width() as i32, frame.height() as i32);
+
+        let ScalerConfig {
+            width,
+            height,
+            pixel_format,
+            algorithm,
+        } = self.config;
+
+        // SAFETY: sws_getCachedContext frees the passed context if it can't be reused.
+        self.ctx = unsafe {
+            sys::sws_getCachedContext(
+                self.ctx,
+                src_width,
+                src_height,
+                src_format.as_av(),
+                width as i32,
+                height as i32,
+                pixel_format.as_av(),
+                algorithm.as_flags() as i32,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                ptr::null(),
+            )
+        };
+        if self.ctx.is_null() {
+            return Err(Error::CreateScalerFailed(src_format, pixel_format));
+        }
+
+        let mut out = VideoFrame::alloc(pixel_format, width, height)?;
+
+        // validate_frame checked the plane count against the format, which has at most 4.
+        let mut src_planes = [ptr::null(); 4];
+        let mut src_strides = [0; 4];
+        for i in 0..frame.plane_count() {
+            src_planes[i] = frame.get_plane(i).as_ptr();
+            src_strides[i] = frame.get_stride(i) as i32;
+        }
+
+        // SAFETY: `out` was just allocated so we are the only user of its data.
+        let ret = unsafe {
+            let fr = out.as_mut_ptr();
+            sys::sws_scale(
+                self.ctx,
+                src_planes.as_ptr(),
+                src_strides.as_ptr(),
+                0,
+                src_height,
+                (*fr).data.as_ptr(),
+                (*fr).linesize.as_ptr(),
+            )
+        };
+        if ret < 0 {
+            return Err(Error::ScaleFailed(ret));
+        }
+
+        out.set_pts(frame.pts());
+        out.set_time_base(frame.time_base());
+        out.set_rotation(frame.rotation());
+        out.set_metadata(frame.metadata());
+
+        Ok(out)
+    }
+}
+
+impl ScaleAlgorithm {
+    fn as_flags(&self) -> u32 {
+        match self {
+            ScaleAlgorithm::FastBilinear => sys::SWS_FAST_BILINEAR,
+            ScaleAlgorithm::Bilinear => sys::SWS_BILINEAR,
+            ScaleAlgorithm::Bicubic => sys::SWS_BICUBIC,
+            ScaleAlgorithm::Point => sys::SWS_POINT,
+            ScaleAlgorithm::Area => sys::SWS_AREA,
+            ScaleAlgorithm::Lanczos => sys::SWS_LANCZOS,
+            ScaleAlgorithm::Spline => sys::SWS_SPLINE,
+        }
+    }
+}
+
+impl Drop for Scaler {
+    fn drop(&mut self) {
+        unsafe {
+            sys::sws_freeContext(self.ctx);
+        }
+        self.ctx = ptr::null_mut();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_scale_bgra_to_yuv420p() {
+        let mut frame = VideoFrame::new(PixelFormat::Bgra, 64, 48).unwrap();
+        frame.set_pts(42);
+
+        let mut scaler = Scaler::new(&ScalerConfig {
+            width: 32,
+            height: 24,
+            pixel_format: PixelFormat::Yuv420p,
+            algorithm: ScaleAlgorithm::Bilinear,
+        });
+
+        for _ in 0..2 {
+            let out = scaler.scale(&frame).unwrap();
+            assert_eq!(out.width(), 32);
+            assert_eq!(out.height(), 24);
+            assert_eq!(out.pixel_format(), PixelFormat::Yuv420p);
+            assert_eq!(out.pts(), 42);
+        }
+    }
+
+    #[test]
+    fn test_scale_rejects_short_planes() {
+        struct ShortFrame(Vec<u8>);
+
+        impl Frame for ShortFrame {
+            type Droppable = Vec<u8>;
+
+            fn width(&self) -> usize {
+                64
+            }
+
+            fn height(&self) -> usize {
+                48
+            }
+
+            fn pixel_format(&self) -> PixelFormat {
+                PixelFormat::Bgra
+            }
+
+            fn plane_count(&self) -> usize {
+                1
+            }
+
+            fn get_plane(&self, _i: usize) -> &[u8] {
+                &self.0
+            }
+
+            fn get_stride(&self, _i: usize) -> usize {
+                64 * 4
+            }
+
+            fn pts(&self) -> i64 {
+                0
+            }
+
+            fn rotation(&self) -> usize {
+                0
+            }
+
+            fn into_droppable(self) -> Self::Droppable {
+                self.0
+            }
+        }
+
+        let mut scaler = Scaler::new(&ScalerConfig {
+            width: 32,
+            height: 24,
+            pixel_format: PixelFormat::Yuv420p,
+            algorithm: ScaleAlgorithm::Bilinear,
+        });
+        assert!(matches!(
+            scaler.scale(&ShortFrame(vec![0; 64 * 4 * 47])),
+            Err(Error::PlaneTooSmall(0, _, _))
+        ));
+    }
+}
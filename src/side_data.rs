@@ -0,0 +1,476 @@
+use std::borrow::Cow;
+use std::mem;
+use std::ptr;
+use std::slice;
+
+use super::{sys, Error, Rational};
+
+/// Side data attached to a [`Packet`](crate::Packet).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketSideData<'a> {
+    /// New codec extradata, e.g. parameter sets after the encoder or the source changed them.
+    NewExtradata(&'a [u8]),
+    /// Changed stream parameters, see `AV_PKT_DATA_PARAM_CHANGE`.
+    ParamChange(&'a [u8]),
+    /// Encoder statistics, see `AV_PKT_DATA_QUALITY_STATS`.
+    QualityStats(&'a [u8]),
+    /// Number of samples to skip, see `AV_PKT_DATA_SKIP_SAMPLES`.
+    SkipSamples(&'a [u8]),
+    /// A 3x3 transformation matrix to apply when displaying the frame, see libavutil/display.h.
+    DisplayMatrix([i32; 9]),
+    /// ATSC A53 Part 4 closed captions.
+    A53ClosedCaptions(&'a [u8]),
+    /// Any other type, identified by its `AVPacketSideDataType` value. This can be read from
+    /// encoded packets, the decoder skips it.
+    Other(u32, &'a [u8]),
+}
+
+impl<'a> PacketSideData<'a> {
+    /// **SAFETY:** `sd` must describe valid data for `'a`.
+    pub(crate) unsafe fn from_av(sd: &'a sys::AVPacketSideData) -> Self {
+        use sys::AVPacketSideDataType::*;
+
+        let data = if sd.data.is_null() {
+            &[]
+        } else {
+            slice::from_raw_parts(sd.data, sd.size)
+        };
+
+        match sd.type_ {
+            AV_PKT_DATA_NEW_EXTRADATA => PacketSideData::NewExtradata(data),
+            AV_PKT_DATA_PARAM_CHANGE => PacketSideData::ParamChange(data),
+            AV_PKT_DATA_QUALITY_STATS => PacketSideData::QualityStats(data),
+            AV_PKT_DATA_SKIP_SAMPLES => PacketSideData::SkipSamples(data),
+            AV_PKT_DATA_DISPLAYMATRIX if data.len() >= mem::size_of::<[i32; 9]>() => {
+                PacketSideData::DisplayMatrix(ptr::read_unaligned(data.as_ptr().cast()))
+            }
+            AV_PKT_DATA_A53_CC => PacketSideData::A53ClosedCaptions(data),
+            kind => PacketSideData::Other(kind as u32, data),
+        }
+    }
+
+    /// The libavcodec type and the data, or the raw type for [`PacketSideData::Other`].
+    pub(crate) fn as_av(&self) -> Result<(sys::AVPacketSideDataType, Cow<'a, [u8]>), u32> {
+        use sys::AVPacketSideDataType::*;
+
+        let (kind, data) = match *self {
+            PacketSideData::NewExtradata(data) => (AV_PKT_DATA_NEW_EXTRADATA, data),
+            PacketSideData::ParamChange(data) => (AV_PKT_DATA_PARAM_CHANGE, data),
+            PacketSideData::QualityStats(data) => (AV_PKT_DATA_QUALITY_STATS, data),
+            PacketSideData::SkipSamples(data) => (AV_PKT_DATA_SKIP_SAMPLES, data),
+            PacketSideData::DisplayMatrix(matrix) => {
+                let data = matrix.iter().flat_map(|v| v.to_ne_bytes()).collect();
+                return Ok((AV_PKT_DATA_DISPLAYMATRIX, Cow::Owned(data)));
+            }
+            PacketSideData::A53ClosedCaptions(data) => (AV_PKT_DATA_A53_CC, data),
+            PacketSideData::Other(kind, _) => return Err(kind),
+        };
+
+        Ok((kind, Cow::Borrowed(data)))
+    }
+}
+
+/// Encoder statistics of a packet, decoded from [`PacketSideData::QualityStats`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct QualityStats {
+    /// Average quantizer of the frame.
+    pub qp: f64,
+    pub picture_type: PictureType,
+    /// Sum of squared errors per plane, if the encoder was configured with `psnr`.
+    pub errors: Vec<u64>,
+}
+
+/// The type of a coded picture.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PictureType {
+    I,
+    P,
+    B,
+    /// MPEG-4 S(GMC)-VOP.
+    S,
+    /// Switching intra.
+    Si,
+    /// Switching predicted.
+    Sp,
+    /// BI type.
+    Bi,
+    Unknown,
+}
+
+impl QualityStats {
+    /// Parse the `AV_PKT_DATA_QUALITY_STATS` layout: a 32 bit quality (QP * `FF_QP2LAMBDA`),
+    /// the picture type, the number of error values, two reserved bytes and the 64 bit errors,
+    /// all little endian.
+    pub(crate) fn parse(data: &[u8]) -> Option<Self> {
+        const FF_QP2LAMBDA: f64 = 118.0;
+
+        let quality = u32::from_le_bytes(data.get(0..4)?.try_into().ok()?);
+        let picture_type = PictureType::from_av(*data.get(4)? as i32);
+        let error_count = *data.get(5)? as usize;
+
+        let errors = data
+            .get(8..)?
+            .chunks_exact(8)
+            .take(error_count)
+            .map(|e| u64::from_le_bytes(e.try_into().unwrap()))
+            .collect();
+
+        Some(QualityStats {
+            qp: quality as f64 / FF_QP2LAMBDA,
+            picture_type,
+            errors,
+        })
+    }
+}
+
+impl PictureType {
+    pub(crate) fn from_av(value: i32) -> Self {
+        use sys::AVPictureType::*;
+
+        match value {
+            v if v == AV_PICTURE_TYPE_I as i32 => PictureType::I,
+            v if v == AV_PICTURE_TYPE_P as i32 => PictureType::P,
+            v if v == AV_PICTURE_TYPE_B as i32 => PictureType::B,
+            v if v == AV_PICTURE_TYPE_S as i32 => PictureType::S,
+            v if v == AV_PICTURE_TYPE_SI as i32 => PictureType::Si,
+            v if v == AV_PICTURE_TYPE_SP as i32 => PictureType::Sp,
+            v if v == AV_PICTURE_TYPE_BI as i32 => PictureType::Bi,
+            _ => PictureType::Unknown,
+        }
+    }
+}
+
+/// Side data attached to a [`Frame`](crate::Frame).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameSideData<'a> {
+    /// H.264/H.265 user data unregistered SEI: a 16 byte UUID followed by the payload.
+    SeiUnregistered(&'a [u8]),
+    /// ATSC A53 Part 4 closed captions, i.e. CEA-708 cc_data triplets.
+    A53ClosedCaptions(&'a [u8]),
+    /// HDR mastering display color volume.
+    MasteringDisplay(MasteringDisplayMetadata),
+    /// HDR content light level.
+    ContentLightLevel(ContentLightLevel),
+    /// A 3x3 transformation matrix to apply when displaying the frame, see libavutil/display.h.
+    DisplayMatrix([i32; 9]),
+    /// Regions to encode at a different quality than the rest of the frame.
+    RegionsOfInterest(Vec<RegionOfInterest>),
+    /// Motion vectors, exported when `DecoderConfig::export_motion_vectors` is set. These can't
+    /// be passed to the encoder.
+    MotionVectors(Vec<MotionVector>),
+    /// Any other type, identified by its `AVFrameSideDataType` value. This can be read from
+    /// decoded frames but not passed to the encoder.
+    Other(u32, &'a [u8]),
+}
+
+/// SMPTE 2086 mastering display color volume.
+///
+/// Chromaticities are CIE 1931 xy coordinates, luminance is in cd/m².
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MasteringDisplayMetadata {
+    /// The red, green and blue primaries as `[x, y]`.
+    pub display_primaries: Option<[[Rational; 2]; 3]>,
+    pub white_point: Option<[Rational; 2]>,
+    pub min_luminance: Option<Rational>,
+    pub max_luminance: Option<Rational>,
+}
+
+/// Content light level (CTA-861.3), in cd/m².
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContentLightLevel {
+    /// Maximum content light level.
+    pub max_cll: u32,
+    /// Maximum frame average light level.
+    pub max_fall: u32,
+}
+
+/// A region of the frame to encode at a different quality.
+///
+/// Supported by libx265, libvpx and libx264. libx264 needs adaptive quantization, which the
+/// `ultrafast` preset the encoder uses turns off, so set the `aq-mode` option or another
+/// `preset` in [`EncoderConfig::options`]. Otherwise encoding a frame with regions fails with
+/// [`Error::RoiNotSupported`]. The encoder rounds the region to its block size.
+///
+/// [`EncoderConfig::options`]: crate::EncoderConfig::options
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegionOfInterest {
+    /// Distance in pixels from the top edge of the frame to the top of the region.
+    pub top: usize,
+    /// Distance in pixels from the top edge of the frame to the bottom of the region.
+    pub bottom: usize,
+    /// Distance in pixels from the left edge of the frame to the left of the region.
+    pub left: usize,
+    /// Distance in pixels from the left edge of the frame to the right of the region.
+    pub right: usize,
+    /// Quantizer offset in the range -1 to 1, scaled to the quantizer range of the encoder.
+    /// Negative values mean better quality.
+    pub qoffset: Rational,
+}
+
+/// A motion vector of a block, see libavutil/motion_vector.h.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MotionVector {
+    /// Negative if the source is in the past, positive if in the future.
+    pub source: i32,
+    /// Width of the block.
+    pub w: u8,
+    /// Height of the block.
+    pub h: u8,
+    /// Absolute source position, may be outside the frame.
+    pub src_x: i16,
+    pub src_y: i16,
+    /// Absolute destination position, may be outside the frame.
+    pub dst_x: i16,
+    pub dst_y: i16,
+    /// Motion vector, `src = dst + motion / motion_scale`.
+    pub motion_x: i32,
+    pub motion_y: i32,
+    pub motion_scale: u16,
+}
+
+impl<'a> FrameSideData<'a> {
+    /// **SAFETY:** `sd` must describe valid data for `'a`.
+    pub(crate) unsafe fn from_av(sd: &'a sys::AVFrameSideData) -> Self {
+        use sys::AVFrameSideDataType::*;
+
+        let data = if sd.data.is_null() {
+            &[]
+        } else {
+            slice::from_raw_parts(sd.data, sd.size)
+        };
+
+        match sd.type_ {
+            AV_FRAME_DATA_SEI_UNREGISTERED => FrameSideData::SeiUnregistered(data),
+            AV_FRAME_DATA_A53_CC => FrameSideData::A53ClosedCaptions(data),
+            AV_FRAME_DATA_MASTERING_DISPLAY_METADATA
+                if data.len() >= mem::size_of::<sys::AVMasteringDisplayMetadata>() =>
+            {
+                let m: sys::AVMasteringDisplayMetadata = ptr::read_unaligned(data.as_ptr().cast());
+                FrameSideData::MasteringDisplay(MasteringDisplayMetadata::from(m))
+            }
+            AV_FRAME_DATA_CONTENT_LIGHT_LEVEL
+                if data.len() >= mem::size_of::<sys::AVContentLightMetadata>() =>
+            {
+                let c: sys::AVContentLightMetadata = ptr::read_unaligned(data.as_ptr().cast());
+                FrameSideData::ContentLightLevel(ContentLightLevel {
+                    max_cll: c.MaxCLL,
+                    max_fall: c.MaxFALL,
+                })
+            }
+            AV_FRAME_DATA_DISPLAYMATRIX if data.len() >= mem::size_of::<[i32; 9]>() => {
+                FrameSideData::DisplayMatrix(ptr::read_unaligned(data.as_ptr().cast()))
+            }
+            AV_FRAME_DATA_REGIONS_OF_INTEREST if !data.is_empty() => {
+                // Each entry starts with its own size, which may grow in later versions.
+                let first: sys::AVRegionOfInterest = ptr::read_unaligned(data.as_ptr().cast());
+                let stride = (first.self_size as usize).max(1);
+                let regions = data
+                    .chunks_exact(stride)
+                    .filter(|chunk| chunk.len() >= mem::size_of::<sys::AVRegionOfInterest>())
+                    .map(|chunk| {
+                        let roi: sys::AVRegionOfInterest =
+                            ptr::read_unaligned(chunk.as_ptr().cast());
+                        RegionOfInterest {
+                            top: roi.top as usize,
+                            bottom: roi.bottom as usize,
+                            left: roi.left as usize,
+                            right: roi.right as usize,
+                            qoffset: roi.qoffset.into(),
+                        }
+                    })
+                    .collect();
+                FrameSideData::RegionsOfInterest(regions)
+            }
+            AV_FRAME_DATA_MOTION_VECTORS => {
+                let count = data.len() / mem::size_of::<sys::AVMotionVector>();
+                let vectors = (0..count)
+                    .map(|i| {
+                        let mv: sys::AVMotionVector =
+                            ptr::read_unaligned(data.as_ptr().cast::<sys::AVMotionVector>().add(i));
+                        MotionVector::from(mv)
+                    })
+                    .collect();
+                FrameSideData::MotionVectors(vectors)
+            }
+            kind => FrameSideData::Other(kind as u32, data),
+        }
+    }
+
+    /// Attach a copy of the side data to `frame`.
+    ///
+    /// **SAFETY:** `frame` must point to a valid AVFrame.
+    pub(crate) unsafe fn add_to(&self, frame: *mut sys::AVFrame) -> Result<(), Error> {
+        use sys::AVFrameSideDataType::*;
+
+        match self {
+            FrameSideData::SeiUnregistered(data) => {
+                add_bytes(frame, AV_FRAME_DATA_SEI_UNREGISTERED, data)
+            }
+            FrameSideData::A53ClosedCaptions(data) => add_bytes(frame, AV_FRAME_DATA_A53_CC, data),
+            FrameSideData::MasteringDisplay(m) => {
+                add_value(frame, AV_FRAME_DATA_MASTERING_DISPLAY_METADATA, m.as_av())
+            }
+            FrameSideData::ContentLightLevel(c) => add_value(
+                frame,
+                AV_FRAME_DATA_CONTENT_LIGHT_LEVEL,
+                sys::AVContentLightMetadata {
+                    MaxCLL: c.max_cll,
+                    MaxFALL: c.max_fall,
+                },
+            ),
+            FrameSideData::DisplayMatrix(matrix) => {
+                add_value(frame, AV_FRAME_DATA_DISPLAYMATRIX, *matrix)
+            }
+            FrameSideData::RegionsOfInterest(regions) => {
+                let size = mem::size_of::<sys::AVRegionOfInterest>();
+                let dst = new_side_data(
+                    frame,
+                    AV_FRAME_DATA_REGIONS_OF_INTEREST,
+                    size * regions.len(),
+                )?;
+                for (i, roi) in regions.iter().enumerate() {
+                    let roi = sys::AVRegionOfInterest {
+                        self_size: size as u32,
+                        top: roi.top as i32,
+                        bottom: roi.bottom as i32,
+                        left: roi.left as i32,
+                        right: roi.right as i32,
+                        qoffset: roi.qoffset.into(),
+                    };
+                    ptr::write_unaligned(dst.add(i * size).cast(), roi);
+                }
+
+                Ok(())
+            }
+            FrameSideData::MotionVectors(_) => Err(Error::UnsupportedSideData(
+                AV_FRAME_DATA_MOTION_VECTORS as u32,
+            )),
+            FrameSideData::Other(kind, _) => Err(Error::UnsupportedSideData(*kind)),
+        }
+    }
+}
+
+/// Read all side data of `frame`.
+///
+/// **SAFETY:** `frame` must point to a valid AVFrame that outlives `'a`.
+pub(crate) unsafe fn frame_side_data<'a>(frame: *const sys::AVFrame) -> Vec<FrameSideData<'a>> {
+    let count = (*frame).nb_side_data as usize;
+    if count == 0 {
+        return vec![];
+    }
+
+    slice::from_raw_parts((*frame).side_data, count)
+        .iter()
+        .map(|sd| FrameSideData::from_av(&**sd))
+        .collect()
+}
+
+unsafe fn new_side_data(
+    frame: *mut sys::AVFrame,
+    kind: sys::AVFrameSideDataType,
+    size: usize,
+) -> Result<*mut u8, Error> {
+    let sd = sys::av_frame_new_side_data(frame, kind, size);
+    if sd.is_null() {
+        return Err(Error::AlllocateFailed("av_frame_new_side_data"));
+    }
+
+    Ok((*sd).data)
+}
+
+unsafe fn add_bytes(
+    frame: *mut sys::AVFrame,
+    kind: sys::AVFrameSideDataType,
+    data: &[u8],
+) -> Result<(), Error> {
+    let dst = new_side_data(frame, kind, data.len())?;
+    ptr::copy_nonoverlapping(data.as_ptr(), dst, data.len());
+
+    Ok(())
+}
+
+unsafe fn add_value<T>(
+    frame: *mut sys::AVFrame,
+    kind: sys::AVFrameSideDataType,
+    value: T,
+) -> Result<(), Error> {
+    let dst = new_side_data(frame, kind, mem::size_of::<T>())?;
+    ptr::write_unaligned(dst.cast(), value);
+
+    Ok(())
+}
+
+impl MasteringDisplayMetadata {
+    fn as_av(&self) -> sys::AVMasteringDisplayMetadata {
+        let zero = sys::AVRational { num: 0, den: 1 };
+        let primaries = self.display_primaries.zip(self.white_point);
+        let luminance = self.min_luminance.zip(self.max_luminance);
+
+        sys::AVMasteringDisplayMetadata {
+            display_primaries: primaries
+                .map(|(p, _)| p.map(|xy| xy.map(Into::into)))
+                .unwrap_or([[zero; 2]; 3]),
+            white_point: primaries
+                .map(|(_, w)| w.map(Into::into))
+                .unwrap_or([zero; 2]),
+            min_luminance: luminance.map(|(min, _)| min.into()).unwrap_or(zero),
+            max_luminance: luminance.map(|(_, max)| max.into()).unwrap_or(zero),
+            has_primaries: primaries.is_some() as i32,
+            has_luminance: luminance.is_some() as i32,
+        }
+    }
+}
+
+impl From<sys::AVMasteringDisplayMetadata> for MasteringDisplayMetadata {
+    fn from(m: sys::AVMasteringDisplayMetadata) -> Self {
+        let has_primaries = m.has_primaries != 0;
+        let has_luminance = m.has_luminance != 0;
+
+        MasteringDisplayMetadata {
+            display_primaries: has_primaries
+                .then(|| m.display_primaries.map(|p| p.map(Rational::from))),
+            white_point: has_primaries.then(|| m.white_point.map(Rational::from)),
+            min_luminance: has_luminance.then(|| m.min_luminance.into()),
+            max_luminance: has_luminance.then(|| m.max_luminance.into()),
+        }
+    }
+}
+
+impl From<sys::AVMotionVector> for MotionVector {
+    fn from(mv: sys::AVMotionVector) -> Self {
+        MotionVector {
+            source: mv.source,
+            w: mv.w,
+            h: mv.h,
+            src_x: mv.src_x,
+            src_y: mv.src_y,
+            dst_x: mv.dst_x,
+            dst_y: mv.dst_y,
+            motion_x: mv.motion_x,
+            motion_y: mv.motion_y,
+            motion_scale: mv.motion_scale,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_quality_stats() {
+        let mut data = vec![];
+        data.extend_from_slice(&(26 * 118u32).to_le_bytes());
+        data.extend_from_slice(&[2, 3, 0, 0]);
+        for e in [100u64, 20, 30] {
+            data.extend_from_slice(&e.to_le_bytes());
+        }
+
+        let stats = QualityStats::parse(&data).unwrap();
+        assert_eq!(stats.qp, 26.0);
+        assert_eq!(stats.picture_type, PictureType::P);
+        assert_eq!(stats.errors, vec![100, 20, 30]);
+
+        assert_eq!(QualityStats::parse(&data[..3]), None);
+    }
+}
@@ -0,0 +1,172 @@
+use crate::{
+    Codec, CodecKind, Decoder, Encoder, EncoderConfig, Error, Metadata, Packet, PacketSideData,
+    PaddedDataImpl, PixelFormat, RateControl, Rational, VideoFrame,
+};
+
+pub(crate) fn encoder(name: &str) -> Codec {
+    Codec::list(CodecKind::Encoder)
+        .find(|c| c.name() == name)
+        .unwrap()
+}
+
+pub(crate) fn x264() -> Codec {
+    encoder("libx264")
+}
+
+pub(crate) fn h264() -> Codec {
+    Codec::list(CodecKind::Decoder)
+        .find(|c| c.name() == "h264")
+        .unwrap()
+}
+
+/// A small stream with the settings the crate picks for real time use.
+pub(crate) fn test_config() -> EncoderConfig {
+    EncoderConfig {
+        rate_control: RateControl::Vbr {
+            bitrate: 500_000,
+            max_bitrate: None,
+        },
+        min_qp: None,
+        max_qp: None,
+        width: 64,
+        height: 48,
+        time_base: Rational::new(1, 30),
+        framerate: Rational::new(30, 1),
+        thread_count: 1,
+        max_b_frames: 0,
+        keyframe_distance: 300,
+        global_header: false,
+        repeat_headers: false,
+        psnr: false,
+        pixel_format: PixelFormat::Yuv420p,
+        options: vec![],
+    }
+}
+
+/// A black Yuv420p frame.
+pub(crate) fn test_frame(width: usize, height: usize, pts: i64) -> VideoFrame {
+    let mut frame = VideoFrame::new(PixelFormat::Yuv420p, width, height).unwrap();
+    frame.set_pts(pts);
+    frame
+}
+
+/// An owned copy of an encoded packet, which can be fed to a decoder.
+pub(crate) struct TestPacket {
+    pub(crate) data: PaddedDataImpl,
+    pub(crate) pts: i64,
+    pub(crate) dts: i64,
+    pub(crate) duration: i64,
+    pub(crate) keyframe: bool,
+    pub(crate) rotation: usize,
+    pub(crate) metadata: Option<Metadata>,
+    pub(crate) time_base: Option<Rational>,
+    pub(crate) resolution_change: Option<(usize, usize)>,
+    pub(crate) side_data: Vec<PacketSideData<'static>>,
+}
+
+impl TestPacket {
+    pub(crate) fn new(packet: Result<impl Packet<[u8]>, Error>) -> Self {
+        let packet = packet.unwrap();
+        TestPacket {
+            data: PaddedDataImpl::from(packet.data()),
+            pts: packet.pts(),
+            dts: packet.dts(),
+            duration: packet.duration(),
+            keyframe: packet.keyframe(),
+            rotation: packet.rotation(),
+            metadata: packet.metadata(),
+            time_base: packet.time_base(),
+            resolution_change: packet.resolution_change(),
+            side_data: vec![],
+        }
+    }
+}
+
+impl Packet<PaddedDataImpl> for TestPacket {
+    type Droppable = PaddedDataImpl;
+
+    fn data(&self) -> &PaddedDataImpl {
+        &self.data
+    }
+
+    fn rotation(&self) -> usize {
+        self.rotation
+    }
+
+    fn keyframe(&self) -> bool {
+        self.keyframe
+    }
+
+    fn pts(&self) -> i64 {
+        self.pts
+    }
+
+    fn dts(&self) -> i64 {
+        self.dts
+    }
+
+    fn duration(&self) -> i64 {
+        self.duration
+    }
+
+    fn metadata(&self) -> Option<Metadata> {
+        self.metadata.clone()
+    }
+
+    fn resolution_change(&self) -> Option<(usize, usize)> {
+        self.resolution_change
+    }
+
+    fn time_base(&self) -> Option<Rational> {
+        self.time_base
+    }
+
+    fn side_data(&self) -> Vec<PacketSideData<'_>> {
+        self.side_data.clone()
+    }
+
+    fn into_droppable(self) -> Self::Droppable {
+        self.data
+    }
+}
+
+/// Encode a black frame of the encoder's size for each pts.
+pub(crate) fn encode_frames(
+    enc: &mut Encoder,
+    pts: impl IntoIterator<Item = i64>,
+) -> Vec<TestPacket> {
+    let mut packets = vec![];
+    for pts in pts {
+        let frame = test_frame(enc.width(), enc.height(), pts);
+        packets.extend(enc.encode(frame, false).unwrap().map(TestPacket::new));
+    }
+    packets
+}
+
+/// Flush the encoder, taking all the packets it still holds.
+pub(crate) fn flush(enc: &mut Encoder) -> Vec<TestPacket> {
+    enc.flush().unwrap().map(TestPacket::new).collect()
+}
+
+/// Encode `count` frames with libx264, returning the packets and the extradata.
+pub(crate) fn encode_stream(config: &EncoderConfig, count: i64) -> (Vec<TestPacket>, Vec<u8>) {
+    let mut enc = Encoder::new(&x264(), config).unwrap();
+    let mut packets = encode_frames(&mut enc, 0..count);
+    packets.extend(flush(&mut enc));
+
+    (packets, enc.extradata().to_vec())
+}
+
+/// Decode the packets and drain the decoder, returning the number of frames.
+pub(crate) fn decode_count(
+    dec: &mut Decoder,
+    packets: impl IntoIterator<Item = TestPacket>,
+) -> Result<usize, Error> {
+    let mut count = 0;
+    for packet in packets {
+        count += dec.decode(packet)?.collect::<Result<Vec<_>, _>>()?.len();
+    }
+    count += dec.drain()?.collect::<Result<Vec<_>, _>>()?.len();
+
+    Ok(count)
+}
@@ -0,0 +1,348 @@
+use std::ptr;
+
+use crate::side_data::frame_side_data;
+use crate::MAX_PLANES;
+
+use super::{
+    err_code_to_string, sys, Error, Frame, FrameSideData, Metadata, PixelFormat, Rational,
+};
+
+/// A video frame owning its pixel data.
+///
+/// The planes are allocated by libavutil with the alignment and padding libavcodec expects, which
+/// means the frame can be handed to [`Encoder::encode`](crate::Encoder::encode) without copying.
+pub struct VideoFrame {
+    ptr: *mut sys::AVFrame,
+    rotation: usize,
+    metadata: Option<Metadata>,
+}
+
+// SAFETY: AVFrame is fine to send between threads.
+unsafe impl Send for VideoFrame {}
+unsafe impl Sync for VideoFrame {}
+
+impl VideoFrame {
+    /// Allocate a frame with zeroed planes.
+    pub fn new(pixel_format: PixelFormat, width: usize, height: usize) -> Result<Self, Error> {
+        let mut frame = VideoFrame::alloc(pixel_format, width, height)?;
+        for i in 0..frame.plane_count() {
+            frame.plane_mut(i).fill(0);
+        }
+
+        Ok(frame)
+    }
+
+    /// Allocate a frame leaving the contents of the planes uninitialised.
+    ///
+    /// The caller must fill the planes before reading them.
+    pub(crate) fn alloc(
+        pixel_format: PixelFormat,
+        width: usize,
+        height: usize,
+    ) -> Result<Self, Error> {
+        let mut ptr = unsafe { sys::av_frame_alloc() };
+        if ptr.is_null() {
+            return Err(Error::AlllocateFailed("av_frame_alloc for VideoFrame::new"));
+        }
+
+        unsafe {
+            (*ptr).format = pixel_format.as_av() as i32;
+            (*ptr).width = width as i32;
+            (*ptr).height = height as i32;
+
+            // 0 picks a suitable alignment for the current CPU.
+            let ret = sys::av_frame_get_buffer(ptr, 0);
+            if ret < 0 {
+                sys::av_frame_free(&mut ptr);
+                return Err(Error::AllocateFrameFailed(ret, err_code_to_string(ret)));
+            }
+        }
+
+        Ok(VideoFrame {
+            ptr,
+            rotation: 0,
+            metadata: None,
+        })
+    }
+
+    /// Take ownership of an AVFrame with allocated planes.
+    ///
+    /// **SAFETY:** `ptr` must point to a valid AVFrame with reference counted planes.
+    pub(crate) unsafe fn from_raw(ptr: *mut sys::AVFrame) -> Self {
+        VideoFrame {
+            ptr,
+            rotation: 0,
+            metadata: None,
+        }
+    }
+
+    /// Allocate a frame and fill it from a contiguous buffer.
+    ///
+    /// The buffer holds the planes one after another without any padding between rows, i.e. the
+    /// layout produced by [`VideoFrame::copy_to_buffer`].
+    pub fn from_buffer(
+        pixel_format: PixelFormat,
+        width: usize,
+        height: usize,
+        data: &[u8],
+    ) -> Result<Self, Error> {
+        let needed = Self::buffer_size(pixel_format, width, height);
+        if data.len() < needed {
+            return Err(Error::BufferTooSmall(data.len(), needed));
+        }
+
+        let mut frame = VideoFrame::alloc(pixel_format, width, height)?;
+
+        let mut offset = 0;
+        for i in 0..frame.plane_count() {
+            let line_size = pixel_format.line_size(i, width);
+            let rows = pixel_format.plane_height(i, height);
+            let stride = frame.get_stride(i);
+
+            let plane = frame.plane_mut(i);
+            unsafe {
+                sys::av_image_copy_plane(
+                    plane.as_mut_ptr(),
+                    stride as i32,
+                    data[offset..].as_ptr(),
+                    line_size as i32,
+                    line_size as i32,
+                    rows as i32,
+                );
+            }
+            offset += line_size * rows;
+        }
+
+        Ok(frame)
+    }
+
+    /// Copy the planes into a contiguous buffer without padding between rows.
+    ///
+    /// Returns the number of bytes written, which is [`VideoFrame::buffer_size`] for the frame.
+    pub fn copy_to_buffer(&self, data: &mut [u8]) -> Result<usize, Error> {
+        let pixel_format = self.pixel_format();
+        let (width, height) = (self.width(), self.height());
+
+        let needed = Self::buffer_size(pixel_format, width, height);
+        if data.len() < needed {
+            return Err(Error::BufferTooSmall(data.len(), needed));
+        }
+
+        let mut offset = 0;
+        for i in 0..self.plane_count() {
+            let line_size = pixel_format.line_size(i, width);
+            let rows = pixel_format.plane_height(i, height);
+
+            unsafe {
+                sys::av_image_copy_plane(
+                    data[offset..].as_mut_ptr(),
+                    line_size as i32,
+                    self.get_plane(i).as_ptr(),
+                    self.get_stride(i) as i32,
+                    line_size as i32,
+                    rows as i32,
+                );
+            }
+            offset += line_size * rows;
+        }
+
+        Ok(offset)
+    }
+
+    /// Size in bytes of a contiguous buffer holding a frame with the given format and size.
+    pub fn buffer_size(pixel_format: PixelFormat, width: usize, height: usize) -> usize {
+        (0..pixel_format.plane_count())
+            .map(|i| pixel_format.line_size(i, width) * pixel_format.plane_height(i, height))
+            .sum()
+    }
+
+    /// Mutable access to plane `i`.
+    ///
+    /// If the pixel data is shared, e.g. because an encoder still holds a reference to it, it is
+    /// copied first so that the other users are not affected.
+    pub fn plane_mut(&mut self, i: usize) -> &mut [u8] {
+        assert!(i < self.plane_count());
+
+        // SAFETY:
+        // * The pointer is valid while self is alive.
+        // * After av_frame_make_writable we are the only user of the data.
+        // * The value calculated for `len` is correct
+        unsafe {
+            let ret = sys::av_frame_make_writable(self.ptr);
+            assert!(
+                ret >= 0,
+                "av_frame_make_writable: {}",
+                err_code_to_string(ret)
+            );
+
+            let ptr: *mut u8 = (*self.ptr).data[i];
+            let len = self.get_stride(i) * self.pixel_format().plane_height(i, self.height());
+
+            std::slice::from_raw_parts_mut(ptr, len)
+        }
+    }
+
+    pub(crate) fn as_mut_ptr(&mut self) -> *mut sys::AVFrame {
+        self.ptr
+    }
+
+    pub fn set_pts(&mut self, pts: i64) {
+        // SAFETY: The pointer is valid while self is alive.
+        unsafe { (*self.ptr).pts = pts };
+    }
+
+    /// Set the time base of the pts, `None` meaning the time base of whatever consumes the frame.
+    pub fn set_time_base(&mut self, time_base: Option<Rational>) {
+        let time_base = time_base.unwrap_or(Rational::new(0, 1));
+        // SAFETY: The pointer is valid while self is alive.
+        unsafe { (*self.ptr).time_base = time_base.into() };
+    }
+
+    pub fn set_rotation(&mut self, rotation: usize) {
+        self.rotation = rotation;
+    }
+
+    pub fn set_metadata(&mut self, metadata: Option<Metadata>) {
+        self.metadata = metadata;
+    }
+
+    /// Attach side data, e.g. captions or SEI user data for the encoder to emit in-band.
+    pub fn add_side_data(&mut self, side_data: &FrameSideData<'_>) -> Result<(), Error> {
+        // SAFETY: The pointer is valid while self is alive.
+        unsafe { side_data.add_to(self.ptr) }
+    }
+}
+
+impl Frame for VideoFrame {
+    type Droppable = Self;
+
+    fn width(&self) -> usize {
+        // SAFETY: The pointer is valid while self is alive.
+        unsafe { (*self.ptr).width as usize }
+    }
+
+    fn height(&self) -> usize {
+        // SAFETY: The pointer is valid while self is alive.
+        unsafe { (*self.ptr).height as usize }
+    }
+
+    fn pixel_format(&self) -> PixelFormat {
+        // SAFETY: The pointer is valid while self is alive.
+        let format = unsafe { (*self.ptr).format };
+
+        // The format is only ever set from a PixelFormat.
+        PixelFormat::from_av(format).expect("a supported pixel format")
+    }
+
+    fn plane_count(&self) -> usize {
+        self.pixel_format().plane_count()
+    }
+
+    fn get_plane(&self, i: usize) -> &[u8] {
+        assert!(i < self.plane_count());
+
+        // SAFETY:
+        // * The pointer is valid while self is alive.
+        // * The value calculated for `len` is correct
+        unsafe {
+            let ptr: *const u8 = (*self.ptr).data[i];
+            let len = self.get_stride(i) * self.pixel_format().plane_height(i, self.height());
+
+            std::slice::from_raw_parts(ptr, len)
+        }
+    }
+
+    fn get_stride(&self, i: usize) -> usize {
+        assert!(i < MAX_PLANES);
+
+        // SAFETY: The pointer is valid while self is alive.
+        unsafe {
+            (*self.ptr).linesize[i]
+                .try_into()
+                .expect("Non negative linesize")
+        }
+    }
+
+    fn pts(&self) -> i64 {
+        // SAFETY: The pointer is valid while self is alive.
+        unsafe { (*self.ptr).pts }
+    }
+
+    fn time_base(&self) -> Option<Rational> {
+        // SAFETY: The pointer is valid while self is alive.
+        let time_base = unsafe { (*self.ptr).time_base };
+        (time_base.num != 0).then(|| time_base.into())
+    }
+
+    fn rotation(&self) -> usize {
+        self.rotation
+    }
+
+    fn side_data(&self) -> Vec<FrameSideData<'_>> {
+        // SAFETY: The pointer is valid while self is alive.
+        unsafe { frame_side_data(self.ptr) }
+    }
+
+    fn metadata(&self) -> Option<Metadata> {
+        self.metadata.clone()
+    }
+
+    fn into_droppable(self) -> Self::Droppable {
+        self
+    }
+
+    fn as_avcodec_buf_ref(&self) -> Option<[*mut sys::AVBufferRef; MAX_PLANES]>
+    where
+        Self: Sized,
+    {
+        // SAFETY: The pointer is valid until we run the Drop trait.
+        let buffers = unsafe { (*self.ptr).buf };
+        Some(buffers)
+    }
+}
+
+impl Drop for VideoFrame {
+    fn drop(&mut self) {
+        unsafe {
+            sys::av_frame_free(&mut self.ptr);
+        }
+        self.ptr = ptr::null_mut();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_buffer_round_trip() {
+        let (width, height) = (33, 17);
+        let size = VideoFrame::buffer_size(PixelFormat::Yuv420p, width, height);
+        assert_eq!(size, 33 * 17 + 2 * (17 * 9));
+
+        let data: Vec<u8> = (0..size).map(|i| i as u8).collect();
+        let frame = VideoFrame::from_buffer(PixelFormat::Yuv420p, width, height, &data).unwrap();
+        assert_eq!(frame.get_plane(0)[width], data[width]);
+
+        let mut out = vec![0; size];
+        assert_eq!(frame.copy_to_buffer(&mut out).unwrap(), size);
+        assert_eq!(data, out);
+    }
+
+    #[test]
+    fn test_side_data_round_trip() {
+        let mut frame = VideoFrame::new(PixelFormat::Yuv420p, 16, 16).unwrap();
+
+        let sei = FrameSideData::SeiUnregistered(&[7; 20]);
+        let light = FrameSideData::ContentLightLevel(crate::ContentLightLevel {
+            max_cll: 1000,
+            max_fall: 400,
+        });
+        frame.add_side_data(&sei).unwrap();
+        frame.add_side_data(&light).unwrap();
+        assert_eq!(frame.side_data(), vec![sei, light]);
+
+        let mvs = FrameSideData::MotionVectors(vec![]);
+        assert!(frame.add_side_data(&mvs).is_err());
+    }
+}